@@ -1,19 +1,64 @@
-use clap::{Arg, Command};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use clap::{Arg, ArgMatches, Command};
 use regex::Regex;
 use reqwest::{Client, StatusCode};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::io::copy;
+use std::io::{IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::{Mutex, Semaphore};
+use tokio::sync::{Mutex, Semaphore, mpsc};
 use zip::ZipArchive;
 
 const MAX_RETRIES: u32 = 3;
 const RETRY_DELAY_MS: u64 = 1000;
+/// Repositories at least this large (GitHub's `size`, in KB) get
+/// `--max-retries-large` attempts instead of `MAX_RETRIES`.
+const LARGE_REPO_KB: u32 = 100 * 1024;
+/// `--low-power` settings: the `--parallel` cap, the
+/// `--max-concurrent-extractions-memory` used when no memory limit is given,
+/// and the pause before each download after the first.
+const LOW_POWER_PARALLEL: usize = 2;
+const LOW_POWER_EXTRACTION_MB: u32 = 256;
+const LOW_POWER_PAUSE: Duration = Duration::from_secs(2);
+/// Branches dated when choosing a fallback for a failed default branch.
+const BRANCH_FALLBACK_LIMIT: usize = 10;
+const PARTIAL_STATE_INTERVAL: u64 = 8 * 1024 * 1024;
+const REPO_LOG_FILE: &str = ".magnet.log";
+/// `account.json`, or `.toml`/`.yaml` under `--output-manifest-format`.
+const ACCOUNT_METADATA_STEM: &str = "account";
+const MANIFEST_VERSION: u32 = 1;
+const LARGEST_REPOS_SHOWN: usize = 5;
+const SPECULATIVE_SUFFIX: &str = ".speculative";
+const SPECULATE_FACTOR: u32 = 4;
+const SPECULATE_MIN_SAMPLES: usize = 3;
+const SPECULATE_AFTER_MIN: Duration = Duration::from_secs(30);
 const GITHUB_API_BASE: &str = "https://api.github.com";
 
+/// Prints human-oriented status output, diverted to stderr when stdout is
+/// reserved for machine-readable output.
+macro_rules! status {
+    ($quiet:expr) => {
+        if $quiet {
+            eprintln!();
+        } else {
+            println!();
+        }
+    };
+    ($quiet:expr, $($arg:tt)*) => {
+        if $quiet {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = Command::new("magnet")
@@ -32,14 +77,105 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('t')
                 .help("GitHub personal access token (avoids rate limits)")
                 .value_name("TOKEN")
-                .env("GITHUB_TOKEN"),
+                .env("GITHUB_TOKEN")
+                .global(true),
+        )
+        .arg(
+            Arg::new("auth-from-gh-cli")
+                .long("auth-from-gh-cli")
+                .help("Use the token of the logged-in GitHub CLI (gh auth token) when no --token is set")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("tls-backend")
+                .long("tls-backend")
+                .help("TLS implementation to use (must be compiled in)")
+                .value_parser(["native", "rustls"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("ca-bundle")
+                .long("ca-bundle")
+                .help(
+                    "Trust the CA certificates in this PEM file (e.g. for TLS-inspecting proxies)",
+                )
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            Arg::new("danger-insecure")
+                .long("danger-insecure")
+                .help("Disable TLS certificate verification (DANGEROUS, debugging only)")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print the final summary as JSON on stdout (status output moves to stderr)")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("json-pretty")
+                .long("json-pretty")
+                .help("Indent the --json output for reading instead of printing one line")
+                .action(clap::ArgAction::SetTrue)
+                .requires("json")
+                .global(true),
+        )
+        .arg(
+            Arg::new("summary-json-to")
+                .long("summary-json-to")
+                .help("Also write the final summary as JSON to this file, whatever goes to stdout")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            Arg::new("cluster-duplicates")
+                .long("cluster-duplicates")
+                .help("After downloading, group repositories whose sets of file contents overlap and write the clusters as JSON to this file")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("trial-run-extract")
+                .global(true),
+        )
+        .arg(
+            Arg::new("cluster-threshold")
+                .long("cluster-threshold")
+                .help("Jaccard similarity of file-content sets from which --cluster-duplicates groups two repositories")
+                .value_name("FRACTION")
+                .value_parser(parse_fraction)
+                .default_value("0.8")
+                .requires("cluster-duplicates")
+                .global(true),
+        )
+        .arg(
+            Arg::new("flag-depth")
+                .long("flag-depth")
+                .help("List repositories whose extracted tree is more than this many levels deep in the summary")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("output-manifest-format")
+                .long("output-manifest-format")
+                .help("Format of the manifest and account metadata written to each output directory")
+                .value_parser(["json", "toml", "yaml"])
+                .default_value("json")
+                .global(true),
         )
         .arg(
             Arg::new("language")
                 .long("language")
                 .short('l')
                 .help("Filter by programming language")
-                .value_name("LANG"),
+                .value_name("LANG")
+                .global(true),
         )
         .arg(
             Arg::new("min-stars")
@@ -47,21 +183,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('s')
                 .help("Minimum number of stars")
                 .value_name("NUM")
-                .value_parser(clap::value_parser!(u32)),
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
         )
         .arg(
             Arg::new("only-original")
                 .long("only-original")
                 .short('o')
                 .help("Original repositories only (no forks)")
-                .action(clap::ArgAction::SetTrue),
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
         )
         .arg(
             Arg::new("regex")
                 .long("regex")
                 .short('r')
                 .help("Filter repository names by regex pattern")
-                .value_name("PATTERN"),
+                .value_name("PATTERN")
+                .global(true),
+        )
+        .arg(
+            Arg::new("repo-blocklist-url")
+                .long("repo-blocklist-url")
+                .help("Exclude repositories matching the owner/name or glob patterns listed at this URL")
+                .value_name("URL")
+                .global(true),
+        )
+        .arg(
+            Arg::new("name-contains")
+                .long("name-contains")
+                .help("Keep repositories whose name contains this text (repeatable, case-insensitive)")
+                .value_name("SUBSTR")
+                .action(clap::ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            Arg::new("name-contains-match")
+                .long("name-contains-match")
+                .help("Whether a name must contain any or all of the --name-contains values")
+                .value_parser(["any", "all"])
+                .default_value("any")
+                .global(true),
         )
         .arg(
             Arg::new("max-size")
@@ -69,7 +231,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('m')
                 .help("Maximum repository size in MB")
                 .value_name("MB")
-                .value_parser(clap::value_parser!(u32)),
+                .value_parser(clap::value_parser!(u32))
+                .global(true),
         )
         .arg(
             Arg::new("parallel")
@@ -78,477 +241,8968 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Parallel download count")
                 .value_name("COUNT")
                 .value_parser(clap::value_parser!(usize))
-                .default_value("3"),
+                .default_value("3")
+                .global(true),
+        )
+        .arg(
+            Arg::new("low-power")
+                .long("low-power")
+                .help("Trade speed for less CPU, disk and battery use: at most 2 downloads, a 256 MB extraction budget, and a 2s pause between downloads")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("parallel-ramp")
+                .long("parallel-ramp")
+                .help("Start with one download and open up to --parallel evenly over this many seconds")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("concurrency-per-host")
+                .long("concurrency-per-host")
+                .help("Most archive downloads to run against any single host at once")
+                .value_name("COUNT")
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("follow-forks-upstream")
+                .long("follow-forks-upstream")
+                .help("Download the root of each fork's network instead of the fork")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("only-original")
+                .global(true),
+        )
+        .arg(
+            Arg::new("follow-to-parent")
+                .long("follow-to-parent")
+                .help("With --follow-forks-upstream, stop at the immediate parent instead of the root")
+                .action(clap::ArgAction::SetTrue)
+                .requires("follow-forks-upstream")
+                .global(true),
+        )
+        .arg(
+            Arg::new("branches")
+                .long("branches")
+                .help("Download these branches of each repository into {repo}/{branch}/")
+                .value_name("BRANCHES")
+                .value_delimiter(',')
+                .conflicts_with("latest-tag")
+                .global(true),
+        )
+        .arg(
+            Arg::new("all-branches")
+                .long("all-branches")
+                .help("Download every branch of each repository into {repo}/{branch}/")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["branches", "latest-tag"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("mode")
+                .long("mode")
+                .help("How to fetch repositories: branch archives, bare `git clone --mirror` copies, or auto to pick per repository")
+                .value_parser(["archive", "clone", "auto"])
+                .default_value("archive")
+                .global(true),
+        )
+        .arg(
+            Arg::new("branch-strategy")
+                .long("branch-strategy")
+                .help("How the default branch is resolved: trust it and fall back to other branches if its archive fails (default), trust it with no fallback (metadata), or confirm it with the API first (api)")
+                .value_parser(["default", "metadata", "api"])
+                .default_value("default")
+                .global(true),
+        )
+        .arg(
+            Arg::new("depth")
+                .long("depth")
+                .help("Clone only the last N commits (needs --mode clone or auto)")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("mirror-to")
+                .long("mirror-to")
+                .help("After cloning, `git push --mirror` each repository to this remote ({owner} and {name} are substituted)")
+                .value_name("REMOTE")
+                .global(true),
+        )
+        .arg(
+            Arg::new("compact-small-repos")
+                .long("compact-small-repos")
+                .help("Pack repositories smaller than this into shared small-NNN.zip archives")
+                .value_name("KB")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .conflicts_with_all(["branches", "all-branches"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("ref-in-dirname")
+                .long("ref-in-dirname")
+                .help("Name each extracted directory after the archive's ref and short commit, e.g. repo@main-a1b2c3d")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "compact-small-repos",
+                    "branches",
+                    "all-branches",
+                    "trial-run-extract",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("strip-components")
+                .long("strip-components")
+                .help("Leading path components to drop from archive entries; GitHub archives wrap everything in one directory")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1")
+                .global(true),
+        )
+        .arg(
+            Arg::new("overlay-safe")
+                .long("overlay-safe")
+                .help("Extract only regular files and directories: skip symlinks, device, fifo and socket entries, and overlay whiteout (.wh.*) names")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("resolve-symlinks")
+                .long("resolve-symlinks")
+                .help("Under --overlay-safe, replace symlinks to files inside the repository with copies instead of skipping them")
+                .action(clap::ArgAction::SetTrue)
+                .requires("overlay-safe")
+                .global(true),
+        )
+        .arg(
+            Arg::new("trial-run-extract")
+                .long("trial-run-extract")
+                .help("Download and extract every repository into a temporary directory, verify it, and delete it, keeping only the summary")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "compact-small-repos",
+                    "dedupe",
+                    "dedupe-archives",
+                    "split-dirs",
+                    "watch",
+                    "mirror-to",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("account-metadata")
+                .long("account-metadata")
+                .help("Also save each repository's settings and default-branch protection to account.json in the owner directory")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("language-breakdown")
+                .long("language-breakdown")
+                .help("Fetch each repository's bytes per language from the API and total them in the summary")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("verify-signatures")
+                .long("verify-signatures")
+                .help("Record whether each downloaded ref's head commit has a verified signature")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("latest-tag")
+                .long("latest-tag")
+                .help("Download each repository's highest semver tag instead of its default branch")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("speculative-retry")
+                .long("speculative-retry")
+                .help("Race slow downloads at the end of a pass against a fresh second attempt")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("stream-tarball")
+                .long("stream-tarball")
+                .help("Download tar.gz archives and extract them as they arrive, without writing a temporary archive to disk")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "speculative-retry",
+                    "compact-small-repos",
+                    "ref-in-dirname",
+                    "dedupe-archives",
+                    "cache-dir",
+                    "max-concurrent-extractions-memory",
+                    "max-inflight-bytes",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("stall-timeout")
+                .long("stall-timeout")
+                .help("Abort a download that receives no data for this many seconds (0 disables)")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60")
+                .global(true),
+        )
+        .arg(
+            Arg::new("archive-generation-timeout")
+                .long("archive-generation-timeout")
+                .help("How long to keep polling an archive GitHub answers 202 for while it is being generated (0 fails at once)")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60")
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-idle-retries")
+                .long("max-idle-retries")
+                .help("Times to resume a stalled download before giving up on it")
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("2")
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-retries-large")
+                .long("max-retries-large")
+                .help("Attempts per request for repositories of 100 MB or more, where a dropped connection is costliest to give up on")
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("wait-for-rate-limit")
+                .long("wait-for-rate-limit")
+                .help("When the API quota is exhausted, sleep until it resets instead of failing")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-wait")
+                .long("max-wait")
+                .help("Longest --wait-for-rate-limit sleeps for a reset; a later reset fails as before")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("3900")
+                .requires("wait-for-rate-limit")
+                .global(true),
+        )
+        .arg(
+            Arg::new("order-by-activity")
+                .long("order-by-activity")
+                .help("Download the most recently pushed repositories first")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("rename-on-conflict")
+                .long("rename-on-conflict")
+                .help("When a repository's directory is already taken in this pass, compared case-insensitively, give it a numeric suffix, prefix it with its owner, or skip it with a warning")
+                .value_parser(["suffix", "user-prefix", "skip"])
+                .default_value("suffix")
+                .global(true),
+        )
+        .arg(
+            Arg::new("stream-listing")
+                .long("stream-listing")
+                .help("Start downloading each page of repositories while the next pages are still being listed")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "order-by-activity",
+                    "resume-from",
+                    "follow-forks-upstream",
+                    "print-names",
+                    "print-urls",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("resume-from")
+                .long("resume-from")
+                .help("Skip every repository ordered before this one")
+                .value_name("NAME")
+                .conflicts_with("watch")
+                .global(true),
+        )
+        .arg(
+            Arg::new("dedupe")
+                .long("dedupe")
+                .help("Hardlink byte-identical files across extracted repositories")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("dedupe-archives")
+                .long("dedupe-archives")
+                .help("Hardlink a repository to an earlier one with a byte-identical archive instead of extracting it again")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("ext")
+                .long("ext")
+                .help("Only extract files with this extension (repeatable, case-insensitive)")
+                .value_name("EXT")
+                .action(clap::ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-concurrent-extractions-memory")
+                .long("max-concurrent-extractions-memory")
+                .help("Limit the total uncompressed size of archives being extracted at once")
+                .value_name("MB")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-inflight-bytes")
+                .long("max-inflight-bytes")
+                .help("Limit the combined memory of downloads and extractions in flight (e.g. 512M, 1.5G, 2GiB)")
+                .value_name("SIZE")
+                .value_parser(parse_byte_size)
+                .conflicts_with("max-concurrent-extractions-memory")
+                .global(true),
+        )
+        .arg(
+            Arg::new("keep-empty-dirs")
+                .long("keep-empty-dirs")
+                .help("Recreate every directory in the archive, even if filtering left it empty")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("prune-empty-dirs")
+                .long("prune-empty-dirs")
+                .help("Remove directories that are empty after extraction")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("keep-empty-dirs")
+                .global(true),
+        )
+        .arg(
+            Arg::new("min-free")
+                .long("min-free")
+                .help("Before each download, reclaim partial archives or wait while free disk space is below this")
+                .value_name("MB")
+                .value_parser(clap::value_parser!(u64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("split-dirs")
+                .long("split-dirs")
+                .help("Spread repositories across these directories by a stable hash of their name")
+                .value_name("DIRS")
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .long("cache-dir")
+                .help("Keep downloaded archives in this directory, keyed by repository and commit, and reuse them across runs")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
         )
+        .arg(
+            Arg::new("cache-max-size")
+                .long("cache-max-size")
+                .help("Evict the least recently used cached archives beyond this size (default 10G)")
+                .value_name("SIZE")
+                .value_parser(parse_byte_size)
+                .requires("cache-dir")
+                .global(true),
+        )
+        .arg(
+            Arg::new("language-detect")
+                .long("language-detect")
+                .help("Classify repositories locally by file extensions when GitHub reports no language")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("per-repo-log")
+                .long("per-repo-log")
+                .help("Write a .magnet.log of download and extraction events into each repository")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("print-names")
+                .long("print-names")
+                .help(
+                    "Print matching repositories as owner/name lines and exit without downloading",
+                )
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("print-urls")
+                .long("print-urls")
+                .help("Print clone URLs of matching repositories and exit without downloading")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["print-names", "json"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("metadata-cache")
+                .long("metadata-cache")
+                .help("Take the repository list from this file, written by --prefetch-metadata-only, instead of listing through the API")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("stream-listing")
+                .global(true),
+        )
+        .arg(
+            Arg::new("pin-versions-file")
+                .long("pin-versions-file")
+                .help("After the run, write the commit each downloaded repository was extracted from to this lock file")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            Arg::new("from-lock")
+                .long("from-lock")
+                .help("Download each repository at the commit pinned in this lock file, written by --pin-versions-file")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all([
+                    "latest-tag",
+                    "branches",
+                    "all-branches",
+                    "stream-listing",
+                    "follow-forks-upstream",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("prefetch-metadata-only")
+                .long("prefetch-metadata-only")
+                .help("List, filter and resolve each repository's head commit, write it all to --metadata-cache, and exit without downloading")
+                .action(clap::ArgAction::SetTrue)
+                .requires("metadata-cache")
+                .conflicts_with_all([
+                    "print-names",
+                    "print-urls",
+                    "count-only",
+                    "since-id",
+                    "watch",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("count-only")
+                .long("count-only")
+                .help("Print how many repositories match the filters and exit without downloading")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "print-names",
+                    "print-urls",
+                    "since-id",
+                    "stream-listing",
+                    "watch",
+                ])
+                .global(true),
+        )
+        .arg(
+            Arg::new("since-id")
+                .long("since-id")
+                .help("Report matching repositories added, removed, renamed or resized since the last run's manifest, without downloading")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["print-names", "print-urls", "stream-listing", "watch"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("events-socket")
+                .long("events-socket")
+                .help("Stream progress events as JSON lines to clients of this Unix socket")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Keep running, starting a new pass this many seconds after the last one")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .conflicts_with_all(["print-names", "print-urls"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("fail-fast")
+                .long("fail-fast")
+                .help("Stop the run at the first failed repository, cancelling downloads in flight, and exit nonzero")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("watch")
+                .global(true),
+        )
+        .arg(
+            Arg::new("progress-interval")
+                .long("progress-interval")
+                .help("When output is not a terminal, print one aggregate progress line every SECS instead of a line per repository")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-backoff")
+                .long("max-backoff")
+                .help("Longest wait before retrying a repository that keeps failing under --watch")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("86400")
+                .requires("watch")
+                .global(true),
+        )
+        .subcommand(
+            Command::new("explain")
+                .about("Explain whether the current filters would include a repository")
+                .arg(
+                    Arg::new("user")
+                        .help("Owner of the repository")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("repo")
+                        .help("Repository name")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            Command::new("download")
+                .about("Download exactly the repositories listed as owner/name lines")
+                .arg(
+                    Arg::new("list")
+                        .help("File of owner/name lines, or - to read them from stdin")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Re-print the summary of a previous scrape from its manifest, offline")
+                .arg(
+                    Arg::new("dir")
+                        .help("Output directory of a previous run")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check connectivity, token, rate limit, proxy and output directory")
+                .arg(
+                    Arg::new("dir")
+                        .help("Output directory to check for write access")
+                        .default_value(".")
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            Command::new("json-schema")
+                .about("Print the JSON Schema of the --json summary and its repository records"),
+        )
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
         .get_matches();
 
-    let username = matches.get_one::<String>("username").unwrap();
-    let token = matches.get_one::<String>("token");
-    let language_filter = matches.get_one::<String>("language");
-    let min_stars = matches.get_one::<u32>("min-stars").unwrap_or(&0);
-    let max_size = matches.get_one::<u32>("max-size");
-    let only_original = matches.get_flag("only-original");
-    let regex_pattern = matches.get_one::<String>("regex");
-    let parallel_count = *matches.get_one::<usize>("parallel").unwrap();
-
-    let regex_filter = if let Some(pattern) = regex_pattern {
-        match Regex::new(pattern) {
-            Ok(regex) => Some(regex),
-            Err(e) => {
-                eprintln!("Invalid regex: {}", e);
-                std::process::exit(1);
+    match matches.subcommand() {
+        Some(("explain", sub_matches)) => return explain(sub_matches).await,
+        Some(("doctor", sub_matches)) => return doctor(sub_matches).await,
+        Some(("report", sub_matches)) => return report(sub_matches),
+        Some(("json-schema", _)) => {
+            println!("{}", serde_json::to_string_pretty(&summary_schema())?);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // `download <list>` takes every option the username form does; they are
+    // global, so read them from whichever level was used.
+    let (args, source) = match matches.subcommand() {
+        Some(("download", sub_matches)) => {
+            let list = sub_matches.get_one::<String>("list").unwrap();
+            (sub_matches, RepoSource::List(read_repo_list(list)?))
+        }
+        _ => {
+            let username = matches.get_one::<String>("username").unwrap();
+            (&matches, RepoSource::Owner(username.clone()))
+        }
+    };
+
+    let low_power = args.get_flag("low-power");
+    let mut parallel_count = *args.get_one::<usize>("parallel").unwrap();
+    let parallel_ramp = args
+        .get_one::<u64>("parallel-ramp")
+        .map(|secs| Duration::from_secs(*secs));
+    let min_free = args.get_one::<u64>("min-free").map(|mb| mb * 1024 * 1024);
+    let concurrency_per_host = args.get_one::<usize>("concurrency-per-host").copied();
+    let mut extraction_budget = args
+        .get_one::<u32>("max-concurrent-extractions-memory")
+        .copied();
+    let inflight_budget = args.get_one::<u64>("max-inflight-bytes").copied();
+    let mut download_pause = None;
+    if low_power {
+        parallel_count = parallel_count.min(LOW_POWER_PARALLEL);
+        if extraction_budget.is_none() && inflight_budget.is_none() {
+            extraction_budget = Some(LOW_POWER_EXTRACTION_MB);
+        }
+        download_pause = Some(LOW_POWER_PAUSE);
+    }
+    let events_socket = args.get_one::<PathBuf>("events-socket");
+    let events = match events_socket {
+        Some(path) => Some(EventBus::serve(path)?),
+        None => None,
+    };
+    let dedupe = args.get_flag("dedupe");
+    let dedupe_archives = args.get_flag("dedupe-archives");
+    let archive_cache = args.get_one::<PathBuf>("cache-dir").map(|dir| {
+        let max_bytes = args
+            .get_one::<u64>("cache-max-size")
+            .copied()
+            .unwrap_or(ARCHIVE_CACHE_DEFAULT_MAX);
+        (dir.clone(), max_bytes)
+    });
+    let order_by_activity = args.get_flag("order-by-activity");
+    let rename_on_conflict = match args
+        .get_one::<String>("rename-on-conflict")
+        .map(String::as_str)
+    {
+        Some("user-prefix") => ConflictPolicy::UserPrefix,
+        Some("skip") => ConflictPolicy::Skip,
+        _ => ConflictPolicy::Suffix,
+    };
+    let resume_from = args.get_one::<String>("resume-from").map(String::as_str);
+    let stream_listing = args.get_flag("stream-listing");
+    if stream_listing && matches!(source, RepoSource::List(_)) {
+        return Err("--stream-listing only applies to a username, not a repository list".into());
+    }
+    let follow_forks = args.get_flag("follow-forks-upstream").then(|| {
+        if args.get_flag("follow-to-parent") {
+            Upstream::Parent
+        } else {
+            Upstream::Root
+        }
+    });
+    let language_detect = args.get_flag("language-detect");
+    let per_repo_log = args.get_flag("per-repo-log");
+    let latest_tag = args.get_flag("latest-tag");
+    let branches = if args.get_flag("all-branches") {
+        Some(BranchSelection::All)
+    } else {
+        match args.get_many::<String>("branches") {
+            Some(names) => {
+                let names: Vec<String> = names.cloned().collect();
+                if let Some(bad) = names.iter().find(|name| !is_safe_branch_dir(name)) {
+                    return Err(format!("Invalid branch name for --branches: {}", bad).into());
+                }
+                Some(BranchSelection::List(names))
             }
+            None => None,
+        }
+    };
+    let stall_timeout = match *args.get_one::<u64>("stall-timeout").unwrap() {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let archive_generation_timeout =
+        match *args.get_one::<u64>("archive-generation-timeout").unwrap() {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    let max_idle_retries = *args.get_one::<u32>("max-idle-retries").unwrap();
+    let max_retries_large = args.get_one::<u32>("max-retries-large").copied();
+    let speculative_retry = args.get_flag("speculative-retry");
+    let stream_tarball = args.get_flag("stream-tarball");
+    let compact_small_repos = args.get_one::<u32>("compact-small-repos").copied();
+    let verify_signatures = args.get_flag("verify-signatures");
+    let language_breakdown = args.get_flag("language-breakdown");
+    let trial_run = args.get_flag("trial-run-extract");
+    let strip_components = *args.get_one::<usize>("strip-components").unwrap();
+    let ref_in_dirname = args.get_flag("ref-in-dirname");
+    let overlay_safe = args.get_flag("overlay-safe");
+    let resolve_symlinks = args.get_flag("resolve-symlinks");
+    let branch_strategy = match args
+        .get_one::<String>("branch-strategy")
+        .map(String::as_str)
+    {
+        Some("metadata") => BranchStrategy::Metadata,
+        Some("api") => BranchStrategy::Api,
+        _ => BranchStrategy::Default,
+    };
+    let mode = match args.get_one::<String>("mode").map(String::as_str) {
+        Some("clone") => DownloadMode::Clone,
+        Some("auto") => DownloadMode::Auto,
+        _ => DownloadMode::Archive,
+    };
+    if mode == DownloadMode::Clone {
+        let archive_only = [
+            "branches",
+            "all-branches",
+            "latest-tag",
+            "compact-small-repos",
+            "overlay-safe",
+            "ref-in-dirname",
+            "stream-tarball",
+        ];
+        if let Some(flag) = archive_only
+            .iter()
+            .find(|id| args.value_source(id) == Some(clap::parser::ValueSource::CommandLine))
+        {
+            return Err(format!("--{} cannot be used with --mode clone", flag).into());
         }
+    }
+    let depth = args.get_one::<u32>("depth").copied();
+    if depth.is_some() && mode == DownloadMode::Archive {
+        return Err("--depth requires --mode clone or --mode auto".into());
+    }
+    let mirror_to = args.get_one::<String>("mirror-to").cloned();
+    if mirror_to.is_some() && mode == DownloadMode::Archive {
+        return Err("--mirror-to requires --mode clone or --mode auto".into());
+    }
+    let pinned = match args.get_one::<PathBuf>("from-lock") {
+        Some(path) => Some(Lockfile::load(path)?.repos),
+        None => None,
+    };
+    if pinned.is_some() && mode != DownloadMode::Archive {
+        return Err("--from-lock downloads commit archives and needs --mode archive".into());
+    }
+    if trial_run && mode != DownloadMode::Archive {
+        return Err(
+            "--trial-run-extract verifies archive extraction and needs --mode archive".into(),
+        );
+    }
+    let watch = args
+        .get_one::<u64>("watch")
+        .map(|secs| Duration::from_secs(*secs));
+    let max_backoff = Duration::from_secs(*args.get_one::<u64>("max-backoff").unwrap());
+    let extensions: Vec<String> = args
+        .get_many::<String>("ext")
+        .unwrap_or_default()
+        .map(|ext| ext.trim_start_matches('.').to_lowercase())
+        .collect();
+    let empty_dirs = if args.get_flag("keep-empty-dirs") {
+        EmptyDirs::Keep
+    } else if args.get_flag("prune-empty-dirs") {
+        EmptyDirs::Prune
     } else {
-        None
+        EmptyDirs::Default
     };
+    let mut filters = parse_filters(args);
+    // Each destination holds `{owner}/` trees, each with its own manifest.
+    let trial_root = trial_run.then(trial_extract_root);
+    let bases: Vec<PathBuf> = match (&trial_root, args.get_many::<PathBuf>("split-dirs")) {
+        (Some(root), _) => vec![root.clone()],
+        (None, Some(dirs)) => dirs.cloned().collect(),
+        (None, None) => vec![PathBuf::new()],
+    };
+    let print_names = args.get_flag("print-names");
+    let print_urls = args.get_flag("print-urls");
+    let since_id = args.get_flag("since-id");
+    let count_only = args.get_flag("count-only");
+    let flag_depth = args.get_one::<u32>("flag-depth").copied();
+    let metadata_cache = args
+        .get_one::<PathBuf>("metadata-cache")
+        .map(PathBuf::as_path);
+    let prefetch_metadata = args.get_flag("prefetch-metadata-only");
+    let pin_versions = args
+        .get_one::<PathBuf>("pin-versions-file")
+        .map(PathBuf::as_path);
+    let cluster_duplicates = args.get_one::<PathBuf>("cluster-duplicates").map(|path| {
+        (
+            path.as_path(),
+            *args.get_one::<f64>("cluster-threshold").unwrap(),
+        )
+    });
+    let json = args.get_flag("json");
+    let json_pretty = args.get_flag("json-pretty");
+    let summary_json_to = args
+        .get_one::<PathBuf>("summary-json-to")
+        .map(PathBuf::as_path);
+    let manifest_format = match args
+        .get_one::<String>("output-manifest-format")
+        .map(String::as_str)
+    {
+        Some("toml") => ManifestFormat::Toml,
+        Some("yaml") => ManifestFormat::Yaml,
+        _ => ManifestFormat::Json,
+    };
+    let quiet = print_names || print_urls || count_only || json;
+    // Per-repository lines stay on interactive terminals, where they scroll
+    // harmlessly; only logs get the aggregate.
+    let interactive = if quiet {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    let fail_fast = args.get_flag("fail-fast");
+    let account_metadata = args.get_flag("account-metadata");
+    let progress_interval = args
+        .get_one::<u64>("progress-interval")
+        .filter(|_| !interactive)
+        .map(|secs| Duration::from_secs(*secs));
 
-    fs::create_dir_all(username)?;
-
-    let start_time = Instant::now();
-    let scraper = Scraper::new(token.cloned())?;
-
-    println!("Scanning repositories for: {}", username);
+    let scraper = Scraper::new(Config {
+        dedupe,
+        dedupe_archives,
+        archive_cache,
+        extensions: extensions.clone(),
+        empty_dirs,
+        strip_components,
+        ref_in_dirname,
+        overlay_safe,
+        resolve_symlinks,
+        language_detect,
+        per_repo_log,
+        latest_tag,
+        branches,
+        stall_timeout,
+        archive_generation_timeout,
+        max_idle_retries,
+        max_retries_large,
+        speculative_retry,
+        stream_tarball,
+        compact_small_repos,
+        verify_signatures,
+        language_breakdown,
+        trial_run,
+        mode,
+        branch_strategy,
+        pinned,
+        depth,
+        mirror_to,
+        concurrency_per_host,
+        extraction_budget,
+        inflight_budget,
+        events,
+        ..base_config(args)?
+    })?;
+    if let Some(url) = args.get_one::<String>("repo-blocklist-url") {
+        filters.blocklist = scraper.load_blocklist(url).await;
+    }
 
-    if let Some(lang) = language_filter {
-        println!("Language: {}", lang);
+    match &source {
+        RepoSource::Owner(username) => status!(quiet, "Scanning repositories for: {}", username),
+        RepoSource::List(entries) => {
+            status!(quiet, "Downloading {} listed repositories", entries.len())
+        }
     }
 
-    if *min_stars > 0 {
-        println!("Min stars: {}", min_stars);
+    if let Some(lang) = &filters.language {
+        status!(quiet, "Language: {}", lang);
     }
 
-    if let Some(size) = max_size {
-        println!("Max size: {}MB", size);
+    if filters.min_stars > 0 {
+        status!(quiet, "Min stars: {}", filters.min_stars);
     }
 
-    if only_original {
-        println!("Original only: yes");
+    if let Some(size) = filters.max_size {
+        status!(quiet, "Max size: {}MB", size);
     }
 
-    if let Some(pattern) = regex_pattern {
-        println!("Regex: {}", pattern);
+    if filters.only_original {
+        status!(quiet, "Original only: yes");
     }
 
-    println!("Parallel: {}", parallel_count);
-    if token.is_none() {
-        println!("WARNING: No GitHub token provided - API rate limits apply");
+    if let Some(regex) = &filters.regex {
+        status!(quiet, "Regex: {}", regex.as_str());
     }
-    println!();
 
-    let repos = scraper.fetch_all_repos(username).await?;
-    let filtered_repos = filter_repos(
-        repos,
-        language_filter,
-        *min_stars,
-        max_size,
-        only_original,
-        &regex_filter,
-    );
+    if !filters.blocklist.is_empty() {
+        status!(quiet, "Blocklist: {} patterns", filters.blocklist.len());
+    }
 
-    println!(
-        "Found {} repositories matching criteria",
-        filtered_repos.len()
-    );
+    match follow_forks {
+        Some(Upstream::Root) => status!(quiet, "Forks: follow to network root"),
+        Some(Upstream::Parent) => status!(quiet, "Forks: follow to parent"),
+        None => {}
+    }
 
-    if filtered_repos.is_empty() {
-        println!("No repositories to download");
-        return Ok(());
+    if !filters.name_contains.is_empty() {
+        status!(
+            quiet,
+            "Name contains ({}): {}",
+            if filters.name_contains_all {
+                "all"
+            } else {
+                "any"
+            },
+            filters.name_contains.join(", ")
+        );
     }
 
-    println!();
+    status!(quiet, "Parallel: {}", parallel_count);
+    if low_power {
+        status!(quiet, "Low power: yes");
+    }
+    if let Some(ramp) = parallel_ramp {
+        status!(quiet, "Ramp: over {}s", ramp.as_secs());
+    }
+    if let Some(per_host) = concurrency_per_host {
+        status!(quiet, "Per host: {}", per_host);
+    }
+    if dedupe {
+        status!(quiet, "Dedupe: yes");
+    }
+    if dedupe_archives {
+        status!(quiet, "Dedupe archives: yes");
+    }
+    if stream_tarball {
+        status!(quiet, "Stream tarballs: yes");
+    }
+    if !extensions.is_empty() {
+        status!(quiet, "Extensions: {}", extensions.join(", "));
+    }
+    if language_detect {
+        status!(quiet, "Language detect: yes");
+    }
+    if bases.len() > 1 {
+        let dirs: Vec<String> = bases.iter().map(|d| d.display().to_string()).collect();
+        status!(quiet, "Split across: {}", dirs.join(", "));
+    }
+    if let Some(interval) = watch {
+        status!(quiet, "Watch: every {}s", interval.as_secs());
+    }
+    if let Some(root) = &trial_root {
+        status!(quiet, "Trial run: extracting into {}", root.display());
+    }
+    if scraper.token.is_none() {
+        status!(
+            quiet,
+            "WARNING: No GitHub token provided - API rate limits apply"
+        );
+    }
+    status!(quiet);
 
-    let progress = Arc::new(ProgressTracker::new(filtered_repos.len()));
-    let semaphore = Arc::new(Semaphore::new(parallel_count));
     let scraper = Arc::new(scraper);
-    let mut tasks = Vec::new();
-
-    for repo in filtered_repos {
+    // The first Ctrl-C lets the pass wind down: downloads in flight keep
+    // their partial archives for the next run and the manifest is written.
+    {
         let scraper = scraper.clone();
-        let username = username.clone();
-        let progress = progress.clone();
-        let semaphore = semaphore.clone();
-
-        let task = tokio::spawn(async move {
-            let _permit = semaphore.acquire().await.unwrap();
-            let result = scraper.download_repo(&repo, &username).await;
-            progress.report_completion(repo.name.clone(), result).await;
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            eprintln!("Interrupted: stopping; press Ctrl-C again to exit immediately");
+            scraper.cancel.cancel("interrupted".to_string());
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
         });
-
-        tasks.push(task);
-    }
-
-    for task in tasks {
-        task.await?;
     }
+    let options = PassOptions {
+        source: &source,
+        filters: &filters,
+        bases: &bases,
+        parallel_count,
+        parallel_ramp,
+        download_pause,
+        min_free,
+        follow_forks,
+        order_by_activity,
+        rename_on_conflict,
+        resume_from,
+        stream_listing,
+        metadata_cache,
+        prefetch_metadata,
+        pin_versions,
+        fail_fast,
+        account_metadata,
+        cluster_duplicates,
+        progress_interval,
+        print_names,
+        print_urls,
+        since_id,
+        count_only,
+        quiet,
+        json,
+        json_pretty,
+        summary_json_to,
+        manifest_format,
+        flag_depth,
+    };
 
-    let stats = progress.get_stats().await;
-    let elapsed = start_time.elapsed();
+    let Some(interval) = watch else {
+        let result = run_pass(&scraper, &options, &HashMap::new()).await;
+        if let Some(path) = events_socket {
+            fs::remove_file(path).ok();
+        }
+        if let Some(root) = &trial_root {
+            fs::remove_dir_all(root).ok();
+        }
+        result?;
+        return Ok(());
+    };
 
-    println!();
-    println!("Results:");
-    println!("Downloaded: {}", stats.downloaded);
-    println!("Failed: {}", stats.failed);
-    println!("Total size: {} MB", stats.total_size / 1024 / 1024);
-    println!("Time: {:.2}s", elapsed.as_secs_f64());
-    if stats.downloaded > 0 {
-        println!(
-            "Speed: {:.1} MB/s",
-            (stats.total_size as f64 / 1024.0 / 1024.0) / elapsed.as_secs_f64()
+    // When each failing repository may next be retried. The delay doubles
+    // with every consecutive failure, up to --max-backoff, so a repository
+    // that is broken for good costs one attempt per ceiling period.
+    let mut retry_at: HashMap<String, Instant> = HashMap::new();
+    loop {
+        match run_pass(&scraper, &options, &retry_at).await {
+            Ok(Some(manifest)) => {
+                for record in &manifest.repos {
+                    if record.status == RepoStatus::Downloaded {
+                        retry_at.remove(&record.full_name);
+                    } else {
+                        let doublings = record.consecutive_failures.saturating_sub(1).min(31);
+                        let delay = interval
+                            .saturating_mul(1 << doublings)
+                            .min(max_backoff.max(interval));
+                        retry_at.insert(record.full_name.clone(), Instant::now() + delay);
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) if scraper.cancel.is_cancelled() => return Err(e),
+            Err(e) => eprintln!("WARNING: Pass failed: {}", e),
+        }
+        if let Some(reason) = scraper.cancel.reason() {
+            return Err(reason.into());
+        }
+
+        status!(quiet, "Next pass in {}s", interval.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = scraper.cancel.cancelled() => {}
+        }
+    }
+}
+
+/// Where a pass gets its repositories from.
+enum RepoSource {
+    /// Every repository of this owner.
+    Owner(String),
+    /// Exactly these `(owner, name)` pairs, from `download <list>`.
+    List(Vec<(String, String)>),
+}
+
+impl RepoSource {
+    fn label(&self) -> String {
+        match self {
+            RepoSource::Owner(username) => username.clone(),
+            RepoSource::List(entries) => {
+                let mut owners: Vec<&str> =
+                    entries.iter().map(|(owner, _)| owner.as_str()).collect();
+                owners.sort_unstable();
+                owners.dedup();
+                owners.join(", ")
+            }
+        }
+    }
+}
+
+/// Parses `owner/name` lines from a file, or from stdin for `-`. Blank lines
+/// and `#` comments are ignored, so `--print-names` output can be filtered
+/// or annotated before it is fed back in.
+fn read_repo_list(source: &str) -> Result<Vec<(String, String)>, String> {
+    let text = if source == "-" {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|e| format!("Cannot read stdin: {}", e))?;
+        text
+    } else {
+        fs::read_to_string(source).map_err(|e| format!("Cannot read {}: {}", source, e))?
+    };
+
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry = match line.split_once('/') {
+            Some((owner, name)) if !owner.is_empty() && !name.is_empty() && !name.contains('/') => {
+                (owner.to_string(), name.to_string())
+            }
+            _ => {
+                return Err(format!(
+                    "line {}: expected owner/name, got '{}'",
+                    number + 1,
+                    line
+                ));
+            }
+        };
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+struct PassOptions<'a> {
+    source: &'a RepoSource,
+    filters: &'a Filters,
+    /// Directories the `{owner}/` trees go under (`--split-dirs`).
+    bases: &'a [PathBuf],
+    parallel_count: usize,
+    /// `--parallel-ramp`.
+    parallel_ramp: Option<Duration>,
+    /// Wait between download starts, under `--low-power`.
+    download_pause: Option<Duration>,
+    /// `--min-free`, in bytes.
+    min_free: Option<u64>,
+    follow_forks: Option<Upstream>,
+    order_by_activity: bool,
+    rename_on_conflict: ConflictPolicy,
+    /// `--resume-from`: the repository the pass starts at.
+    resume_from: Option<&'a str>,
+    stream_listing: bool,
+    /// `--metadata-cache`: read in place of listing, or written by
+    /// `--prefetch-metadata-only`.
+    metadata_cache: Option<&'a Path>,
+    prefetch_metadata: bool,
+    /// `--pin-versions-file`: where the lock file goes after the pass.
+    pin_versions: Option<&'a Path>,
+    fail_fast: bool,
+    account_metadata: bool,
+    /// `--cluster-duplicates` report path and `--cluster-threshold`.
+    cluster_duplicates: Option<(&'a Path, f64)>,
+    /// `--progress-interval`, when output is not a terminal.
+    progress_interval: Option<Duration>,
+    print_names: bool,
+    print_urls: bool,
+    /// `--since-id`: report the delta against the manifest and stop.
+    since_id: bool,
+    /// `--count-only`: print the number of matches and stop.
+    count_only: bool,
+    quiet: bool,
+    json: bool,
+    json_pretty: bool,
+    summary_json_to: Option<&'a Path>,
+    manifest_format: ManifestFormat,
+    /// `--flag-depth`.
+    flag_depth: Option<u32>,
+}
+
+/// Lists, filters and downloads the source's repositories once, skipping
+/// those whose `retry_at` time has not come. Returns the pass manifest, or
+/// `None` when nothing was downloaded.
+async fn run_pass(
+    scraper: &Arc<Scraper>,
+    options: &PassOptions<'_>,
+    retry_at: &HashMap<String, Instant>,
+) -> Result<Option<Manifest>, Box<dyn std::error::Error>> {
+    let &PassOptions {
+        source,
+        filters,
+        bases,
+        parallel_count,
+        parallel_ramp,
+        download_pause,
+        min_free,
+        follow_forks,
+        order_by_activity,
+        rename_on_conflict,
+        resume_from,
+        stream_listing,
+        metadata_cache,
+        prefetch_metadata,
+        pin_versions,
+        fail_fast,
+        account_metadata,
+        cluster_duplicates,
+        progress_interval,
+        print_names,
+        print_urls,
+        since_id,
+        count_only,
+        quiet,
+        json,
+        json_pretty,
+        summary_json_to,
+        manifest_format,
+        flag_depth,
+    } = options;
+    let start_time = Instant::now();
+
+    // Under --stream-listing only the first page is fetched up front; the
+    // rest is paged in below while downloads run.
+    let streamed_owner = match source {
+        RepoSource::Owner(username) if stream_listing => Some(username),
+        _ => None,
+    };
+    let cached = match metadata_cache {
+        Some(path) if !prefetch_metadata => Some(MetadataCache::load(path)?),
+        _ => None,
+    };
+    let repos = match (source, streamed_owner, cached) {
+        (_, _, Some(cached)) => {
+            status!(
+                quiet,
+                "Using repositories from {} (listed {}s ago)",
+                metadata_cache.unwrap().display(),
+                unix_now().saturating_sub(cached.fetched_at)
+            );
+            cached.repos_for(scraper, source).await
+        }
+        (_, Some(username), None) => scraper.fetch_repos_page(username, 1).await?,
+        (RepoSource::Owner(username), None, None) => scraper.fetch_all_repos(username).await?,
+        (RepoSource::List(entries), None, None) => scraper.fetch_listed_repos(entries).await,
+    };
+    // The owner's name as GitHub spells it, for the directories.
+    let owner = repos.first().map(|repo| repo.owner().to_string());
+    let repos = match &scraper.pinned {
+        Some(locked) => restrict_to_lock(repos, locked, source, quiet),
+        None => repos,
+    };
+    let mut filtered_repos = filter_repos(repos, filters);
+    if let Some(upstream) = follow_forks {
+        filtered_repos = scraper
+            .follow_forks_upstream(filtered_repos, upstream)
+            .await;
+    }
+    if order_by_activity {
+        order_by_recent_push(&mut filtered_repos);
+    }
+    if let Some(name) = resume_from {
+        let skipped = skip_until(&mut filtered_repos, name)?;
+        status!(
+            quiet,
+            "Resuming from {}, skipping {} repositories",
+            name,
+            skipped
+        );
+    }
+
+    let deferred = defer_failing(&mut filtered_repos, retry_at);
+    if deferred > 0 {
+        status!(
+            quiet,
+            "Deferring {} failing repositories until their backoff expires",
+            deferred
+        );
+    }
+
+    if streamed_owner.is_some() {
+        status!(
+            quiet,
+            "Found {} repositories matching criteria on the first page; listing the rest while downloading",
+            filtered_repos.len()
+        );
+    } else {
+        status!(
+            quiet,
+            "Found {} repositories matching criteria",
+            filtered_repos.len()
+        );
+    }
+
+    if print_names || print_urls {
+        for repo in &filtered_repos {
+            if print_urls {
+                println!("{}", repo.clone_url);
+            } else {
+                println!("{}", repo.full_name);
+            }
+        }
+        return Ok(None);
+    }
+
+    if let Some(path) = metadata_cache
+        && prefetch_metadata
+    {
+        let repos = scraper
+            .resolve_head_commits(filtered_repos, parallel_count)
+            .await;
+        let resolved = repos.iter().filter(|repo| repo.head_sha.is_some()).count();
+        let repo_count = repos.len();
+        MetadataCache {
+            version: METADATA_CACHE_VERSION,
+            owner: source.label(),
+            fetched_at: unix_now(),
+            repos,
+        }
+        .save(path)
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        status!(
+            quiet,
+            "Wrote {} repositories to {} ({} head commits resolved)",
+            repo_count,
+            path.display(),
+            resolved
+        );
+        return Ok(None);
+    }
+
+    if count_only {
+        if json {
+            println!("{}", serde_json::json!({ "count": filtered_repos.len() }));
+        } else {
+            println!("{}", filtered_repos.len());
+        }
+        return Ok(None);
+    }
+
+    if since_id {
+        let mut owners: Vec<&str> = filtered_repos.iter().map(RepoInfo::owner).collect();
+        if let RepoSource::Owner(username) = source {
+            owners.push(owner.as_deref().unwrap_or(username));
+        }
+        owners.sort_unstable();
+        owners.dedup();
+        let manifests: Vec<Manifest> = bases
+            .iter()
+            .flat_map(|base| owners.iter().map(move |owner| base.join(owner)))
+            .filter_map(|dir| Manifest::load(&dir).ok())
+            .collect();
+        if manifests.is_empty() {
+            return Err("--since-id: no manifest from a previous run to compare against".into());
+        }
+        let previous: Vec<&RepoRecord> = manifests.iter().flat_map(|m| &m.repos).collect();
+        let delta = RepoDelta::between(&previous, &filtered_repos);
+        if json {
+            let mut stdout = std::io::stdout().lock();
+            if json_pretty {
+                serde_json::to_writer_pretty(&mut stdout, &delta)?;
+            } else {
+                serde_json::to_writer(&mut stdout, &delta)?;
+            }
+            writeln!(stdout)?;
+        } else {
+            delta.print();
+        }
+        return Ok(None);
+    }
+
+    let owner = match (streamed_owner, owner) {
+        (Some(_), Some(owner)) => Some(owner),
+        (Some(_), None) => {
+            status!(quiet, "No repositories to download");
+            return Ok(None);
+        }
+        (None, _) => None,
+    };
+    if filtered_repos.is_empty() && owner.is_none() {
+        status!(quiet, "No repositories to download");
+        return Ok(None);
+    }
+
+    let target_of =
+        |repo: &RepoInfo| bases[destination_index(&repo.name, bases.len())].join(repo.owner());
+    // Repositories still to be listed may land in any destination.
+    let candidates: Vec<PathBuf> = match &owner {
+        Some(owner) => bases.iter().map(|base| base.join(owner)).collect(),
+        None => filtered_repos.iter().map(target_of).collect(),
+    };
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for candidate in candidates {
+        if !roots.contains(&candidate) {
+            roots.push(candidate);
+        }
+    }
+
+    let mut resumable = 0;
+    for root in &roots {
+        fs::create_dir_all(root)?;
+        resumable += reconcile_partial_downloads(root);
+        if let Some(ref_dirs) = &scraper.ref_dirs
+            && let Ok(manifest) = Manifest::load(root)
+        {
+            ref_dirs.load(root, &manifest.repos);
+        }
+    }
+    if resumable > 0 {
+        status!(quiet, "Resuming {} partial download(s)", resumable);
+    }
+    status!(quiet);
+
+    let manifest_writers: Vec<ManifestWriter> = roots
+        .iter()
+        .map(|root| {
+            let owner = root.file_name().unwrap_or_default().to_string_lossy();
+            ManifestWriter::spawn(root.clone(), &owner, manifest_format)
+        })
+        .collect();
+    let progress = Arc::new(ProgressTracker::new(
+        filtered_repos.len(),
+        quiet,
+        progress_interval,
+        manifest_writers
+            .iter()
+            .map(ManifestWriter::sender)
+            .collect(),
+        scraper.events.clone(),
+    ));
+    let semaphore = match parallel_ramp {
+        Some(_) => Arc::new(Semaphore::new(parallel_count.min(1))),
+        None => Arc::new(Semaphore::new(parallel_count)),
+    };
+    let ramp = parallel_ramp.map(|over| {
+        let semaphore = semaphore.clone();
+        tokio::spawn(ramp_permits(semaphore, parallel_count, over))
+    });
+    // Repositories being downloaded, whose partial archives must survive
+    // low-disk cleanup.
+    let active: Arc<std::sync::Mutex<HashSet<PathBuf>>> = Arc::default();
+    let mut tasks = Vec::new();
+    let cancel = &scraper.cancel;
+    // --account-metadata entries, with the index of their root.
+    let account: Arc<std::sync::Mutex<Vec<(usize, AccountRepo)>>> = Arc::default();
+    scraper.tail.start_pass();
+    let ticker = {
+        let progress = progress.clone();
+        tokio::spawn(async move { progress.tick().await })
+    };
+
+    let (listed_tx, mut listed) = mpsc::unbounded_channel();
+    for repo in filtered_repos {
+        listed_tx.send(repo).ok();
+    }
+    let lister = {
+        let progress = progress.clone();
+        async move {
+            let Some(username) = streamed_owner else {
+                return;
+            };
+            let mut page = 2;
+            let mut total = progress.total();
+            while !cancel.is_cancelled() {
+                let repos = match scraper.fetch_repos_page(username, page).await {
+                    Ok(repos) if repos.is_empty() => break,
+                    Ok(repos) => repos,
+                    Err(e) => {
+                        eprintln!(
+                            "WARNING: Listing stopped at page {}, downloading what was found: {}",
+                            page, e
+                        );
+                        break;
+                    }
+                };
+                let mut admitted = filter_repos(repos, filters);
+                defer_failing(&mut admitted, retry_at);
+                progress.add_total(admitted.len());
+                total += admitted.len();
+                for repo in admitted {
+                    listed_tx.send(repo).ok();
+                }
+                page += 1;
+            }
+            if scraper.token.is_none() {
+                scraper.check_rate_limit().await.ok();
+            }
+            status!(quiet, "Listed {} repositories matching criteria", total);
+        }
+    };
+
+    let scheduler = async {
+        let mut claims = DirClaims::default();
+        while let Some(repo) = listed.recv().await {
+            let root = target_of(&repo);
+            let (dir, conflicts_with) = match claims.claim(&root, &repo, rename_on_conflict) {
+                Claim::Own => (repo.name.clone(), None),
+                Claim::Renamed {
+                    dir,
+                    conflicts_with,
+                } => {
+                    status!(
+                        quiet,
+                        "{} shares its directory with {}, downloading it to {}",
+                        repo.full_name,
+                        conflicts_with,
+                        dir
+                    );
+                    (dir, Some(conflicts_with))
+                }
+                Claim::Skipped { conflicts_with } => {
+                    eprintln!(
+                        "WARNING: Skipping {}: its directory {} is taken by {} (--rename-on-conflict skip)",
+                        repo.full_name,
+                        root.join(&repo.name).display(),
+                        conflicts_with
+                    );
+                    progress.remove_from_total();
+                    continue;
+                }
+            };
+            let scraper = scraper.clone();
+            let destination = roots.iter().position(|r| *r == root).unwrap();
+            let progress = progress.clone();
+            // Acquiring here rather than inside the task keeps downloads starting
+            // in list order, which is what makes --order-by-activity meaningful.
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            if let Some(pause) = download_pause
+                && !tasks.is_empty()
+            {
+                tokio::time::sleep(pause).await;
+            }
+            if cancel.is_cancelled() {
+                break;
+            }
+            if let Some(min_free) = min_free {
+                tokio::select! {
+                    _ = wait_for_free_space(&root, min_free, &roots, &active, quiet) => {}
+                    _ = cancel.cancelled() => break,
+                }
+            }
+
+            let repo_path = root.join(&dir);
+            active.lock().unwrap().insert(repo_path.clone());
+            let active = active.clone();
+            let account = account.clone();
+
+            let task = tokio::spawn(async move {
+                let _permit = permit;
+                progress.report_start(&repo);
+                // A cancelled download leaves its partial archive for the
+                // next run to resume, and no record.
+                let result = tokio::select! {
+                    result = scraper.download_repo(&repo, &root, &dir) => Some(result),
+                    _ = scraper.cancel.cancelled() => None,
+                };
+                if let Some(result) = result {
+                    let result = result.map(|download| Download {
+                        conflicts_with,
+                        ..download
+                    });
+                    if fail_fast && let Err(e) = &result {
+                        scraper
+                            .cancel
+                            .cancel(format!("--fail-fast: {} failed: {}", repo.full_name, e));
+                    }
+                    progress.report_completion(&repo, destination, result).await;
+                    if account_metadata {
+                        let entry = scraper.account_repo(&repo).await;
+                        account.lock().unwrap().push((destination, entry));
+                    }
+                }
+                active.lock().unwrap().remove(&repo_path);
+            });
+
+            tasks.push(task);
+        }
+    };
+    tokio::join!(lister, scheduler);
+    scraper.tail.all_scheduled();
+
+    for task in tasks {
+        task.await?;
+    }
+    ticker.abort();
+    if let Some(ramp) = ramp {
+        ramp.abort();
+    }
+    if let Some(bundles) = &scraper.bundles
+        && let Err(e) = bundles.finish()
+    {
+        eprintln!("WARNING: Could not finish grouped archive: {}", e);
+    }
+
+    let dedupe = scraper.extract.dedupe.as_ref();
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        owner: source.label(),
+        elapsed_secs: start_time.elapsed().as_secs_f64(),
+        files_skipped: scraper.files_skipped.load(Ordering::Relaxed),
+        dedupe_bytes_saved: dedupe.map_or(0, |index| index.bytes_saved()),
+        dedupe_files_linked: dedupe.map_or(0, |index| index.files_linked()),
+        archive_bytes_streamed: scraper.archive_bytes_streamed.load(Ordering::Relaxed),
+        repos: progress.records().await,
+    };
+    drop(progress);
+
+    let mut saved = Vec::new();
+    for writer in manifest_writers {
+        match writer.finish(&manifest).await {
+            Ok(written) => saved.extend(written.repos),
+            Err(e) => eprintln!("WARNING: Could not write manifest: {}", e),
+        }
+    }
+    if account_metadata {
+        let entries = std::mem::take(&mut *account.lock().unwrap());
+        for (index, root) in roots.iter().enumerate() {
+            let repos: Vec<&AccountRepo> = entries
+                .iter()
+                .filter(|(destination, _)| *destination == index)
+                .map(|(_, entry)| entry)
+                .collect();
+            if repos.is_empty() {
+                continue;
+            }
+            let owner = root.file_name().unwrap_or_default().to_string_lossy();
+            if let Err(e) = AccountMetadata::update(root, &owner, repos, manifest_format) {
+                eprintln!(
+                    "WARNING: Could not write {}: {}",
+                    AccountMetadata::file_name(manifest_format),
+                    e
+                );
+            }
+        }
+    }
+
+    if let Some((path, threshold)) = cluster_duplicates {
+        let clusters = DuplicateClusters::find(&roots, &manifest.repos, threshold);
+        status!(quiet);
+        status!(
+            quiet,
+            "Duplicate clusters: {} (written to {})",
+            clusters.clusters.len(),
+            path.display()
         );
+        for cluster in &clusters.clusters {
+            status!(
+                quiet,
+                "  {} (similarity up to {:.0}%)",
+                cluster.repos.join(", "),
+                cluster.max_similarity() * 100.0
+            );
+        }
+        if let Err(e) = clusters.write(path) {
+            eprintln!("WARNING: Could not write {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(path) = pin_versions {
+        let lock = Lockfile::pin(&source.label(), &saved);
+        let unpinned = saved
+            .iter()
+            .filter(|r| r.status == RepoStatus::Downloaded && r.commit.is_none())
+            .count();
+        match lock.save(path) {
+            Ok(()) if unpinned > 0 => status!(
+                quiet,
+                "Pinned {} repositories in {} ({} without a known commit left out)",
+                lock.repos.len(),
+                path.display(),
+                unpinned
+            ),
+            Ok(()) => status!(
+                quiet,
+                "Pinned {} repositories in {}",
+                lock.repos.len(),
+                path.display()
+            ),
+            Err(e) => eprintln!("WARNING: Could not write {}: {}", path.display(), e),
+        }
+    }
+
+    // The writers carried failure streaks over from earlier runs.
+    for record in &mut manifest.repos {
+        if let Some(written) = saved.iter().find(|r| r.full_name == record.full_name) {
+            record.consecutive_failures = written.consecutive_failures;
+        }
+    }
+
+    let summary = Summary::new(&manifest).with_flag_depth(flag_depth);
+    if let Some(events) = &scraper.events {
+        events.emit(Event::Summary(&summary));
+    }
+    emit_summary(&summary, json, json_pretty, summary_json_to)?;
+    if let Some(reason) = cancel.reason() {
+        return Err(reason.into());
+    }
+    Ok(Some(manifest))
+}
+
+/// `--cluster-duplicates` report: repositories grouped by how much of their
+/// content is byte-identical, likely forks or copies of one codebase.
+#[derive(Serialize)]
+struct DuplicateClusters {
+    threshold: f64,
+    clusters: Vec<DuplicateCluster>,
+}
+
+#[derive(Serialize)]
+struct DuplicateCluster {
+    repos: Vec<String>,
+    /// Pairs at or above the threshold that joined the cluster; other
+    /// members may be linked only through a third repository.
+    pairs: Vec<RepoSimilarity>,
+}
+
+#[derive(Serialize)]
+struct RepoSimilarity {
+    a: String,
+    b: String,
+    /// Jaccard index of the two repositories' sets of file hashes.
+    similarity: f64,
+}
+
+impl DuplicateCluster {
+    fn max_similarity(&self) -> f64 {
+        self.pairs
+            .iter()
+            .map(|pair| pair.similarity)
+            .fold(0.0, f64::max)
+    }
+}
+
+impl DuplicateClusters {
+    /// Hashes the files of every extracted repository in `records` and
+    /// links each pair whose hash sets have a Jaccard index of at least
+    /// `threshold`; clusters are the connected groups. Bundled and cloned
+    /// repositories have no extracted tree and are left out.
+    fn find(roots: &[PathBuf], records: &[RepoRecord], threshold: f64) -> Self {
+        let mut names = Vec::new();
+        let mut sets: Vec<HashSet<[u8; 32]>> = Vec::new();
+        for record in records {
+            if record.status != RepoStatus::Downloaded || record.bundle.is_some() || record.cloned {
+                continue;
+            }
+            let owner = record.full_name.split('/').next().unwrap_or_default();
+            let Some(dir) = roots
+                .iter()
+                .filter(|root| root.file_name().is_some_and(|name| name == owner))
+                .map(|root| root.join(record.dir.as_deref().unwrap_or(&record.name)))
+                .find(|dir| dir.is_dir())
+            else {
+                continue;
+            };
+            let mut hashes = HashSet::new();
+            if let Err(e) = hash_tree(&dir, &mut hashes) {
+                eprintln!("WARNING: Could not hash {}: {}", dir.display(), e);
+                continue;
+            }
+            if !hashes.is_empty() {
+                names.push(record.full_name.clone());
+                sets.push(hashes);
+            }
+        }
+
+        // Only repositories sharing at least one file can be similar, so
+        // count shared files through an index instead of comparing every
+        // pair.
+        let mut holders: HashMap<[u8; 32], Vec<usize>> = HashMap::new();
+        for (index, set) in sets.iter().enumerate() {
+            for hash in set {
+                holders.entry(*hash).or_default().push(index);
+            }
+        }
+        let mut shared: HashMap<(usize, usize), u64> = HashMap::new();
+        for repos in holders.values() {
+            for (i, &a) in repos.iter().enumerate() {
+                for &b in &repos[i + 1..] {
+                    *shared.entry((a, b)).or_default() += 1;
+                }
+            }
+        }
+
+        let mut parent: Vec<usize> = (0..names.len()).collect();
+        fn root_of(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        let mut edges = Vec::new();
+        for (&(a, b), &common) in &shared {
+            let union = (sets[a].len() + sets[b].len()) as u64 - common;
+            let similarity = common as f64 / union as f64;
+            if similarity >= threshold {
+                let (root_a, root_b) = (root_of(&mut parent, a), root_of(&mut parent, b));
+                parent[root_a] = root_b;
+                edges.push((a, b, similarity));
+            }
+        }
+
+        let mut grouped: BTreeMap<usize, DuplicateCluster> = BTreeMap::new();
+        for (index, name) in names.iter().enumerate() {
+            let root = root_of(&mut parent, index);
+            let cluster = grouped.entry(root).or_insert_with(|| DuplicateCluster {
+                repos: Vec::new(),
+                pairs: Vec::new(),
+            });
+            cluster.repos.push(name.clone());
+        }
+        for (a, b, similarity) in edges {
+            let root = root_of(&mut parent, a);
+            grouped.get_mut(&root).unwrap().pairs.push(RepoSimilarity {
+                a: names[a].clone(),
+                b: names[b].clone(),
+                similarity,
+            });
+        }
+        let mut clusters: Vec<DuplicateCluster> = grouped
+            .into_values()
+            .filter(|cluster| cluster.repos.len() > 1)
+            .collect();
+        for cluster in &mut clusters {
+            cluster.repos.sort();
+            cluster.pairs.sort_by(|x, y| {
+                y.similarity
+                    .total_cmp(&x.similarity)
+                    .then_with(|| (&x.a, &x.b).cmp(&(&y.a, &y.b)))
+            });
+        }
+        clusters.sort_by(|a, b| {
+            b.repos
+                .len()
+                .cmp(&a.repos.len())
+                .then(a.repos.cmp(&b.repos))
+        });
+
+        Self {
+            threshold,
+            clusters,
+        }
+    }
+
+    /// Writes the report atomically, like `--summary-json-to`.
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let written = fs::File::create(&tmp).and_then(|mut file| {
+            serde_json::to_writer_pretty(&mut file, self)?;
+            writeln!(file)
+        });
+        match written.and_then(|()| fs::rename(&tmp, path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                fs::remove_file(&tmp).ok();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Adds the content hash of every file under `dir` to `hashes`, except a
+/// per-repository log, which differs between copies of the same code.
+fn hash_tree(dir: &Path, hashes: &mut HashSet<[u8; 32]>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            hash_tree(&entry.path(), hashes)?;
+        } else if file_type.is_file() && entry.file_name() != REPO_LOG_FILE {
+            hashes.insert(archive_hash(&entry.path())?);
+        }
+    }
+    Ok(())
+}
+
+/// Parses `--cluster-threshold`: a fraction from 0 to 1.
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!(
+            "invalid fraction '{}' (expected 0 to 1, e.g. 0.8)",
+            value
+        )),
+    }
+}
+
+/// Run-wide cancellation by `--fail-fast` or Ctrl-C, checked by the
+/// scheduler and raced against every download and every request's retries.
+/// The first `cancel` wins and its reason is kept.
+#[derive(Default)]
+struct CancelToken {
+    reason: std::sync::Mutex<Option<String>>,
+    notify: tokio::sync::Notify,
+}
+
+impl CancelToken {
+    fn cancel(&self, reason: String) {
+        let mut current = self.reason.lock().unwrap();
+        if current.is_none() {
+            *current = Some(reason);
+            self.notify.notify_waiters();
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.reason.lock().unwrap().is_some()
+    }
+
+    fn reason(&self) -> Option<String> {
+        self.reason.lock().unwrap().clone()
+    }
+
+    async fn cancelled(&self) {
+        loop {
+            // Registered before the check, so a cancel in between still
+            // wakes it.
+            let notified = self.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+fn report(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = Path::new(matches.get_one::<String>("dir").unwrap());
+    let json = matches.get_flag("json");
+    let json_pretty = matches.get_flag("json-pretty");
+    let summary_json_to = matches.get_one::<PathBuf>("summary-json-to");
+
+    let manifest = Manifest::load(dir)?;
+    status!(json, "Report for: {} ({})", manifest.owner, dir.display());
+
+    emit_summary(
+        &Summary::new(&manifest).with_flag_depth(matches.get_one::<u32>("flag-depth").copied()),
+        json,
+        json_pretty,
+        summary_json_to.map(PathBuf::as_path),
+    )
+}
+
+/// Prints the summary as text, or as JSON: compact on one line for
+/// pipelines by default, indented with `--json-pretty`. `--summary-json-to`
+/// writes the same JSON to a file as well, replacing it atomically so a
+/// reader never sees half a summary.
+fn emit_summary(
+    summary: &Summary,
+    json: bool,
+    pretty: bool,
+    json_to: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        write_summary_json(&mut std::io::stdout().lock(), summary, pretty)?;
+    } else {
+        print_summary(summary);
+    }
+
+    if let Some(path) = json_to {
+        let tmp = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let written = fs::File::create(&tmp)
+            .and_then(|mut file| write_summary_json(&mut file, summary, pretty));
+        if let Err(e) = written.and_then(|()| fs::rename(&tmp, path)) {
+            fs::remove_file(&tmp).ok();
+            return Err(format!("Could not write summary to {}: {}", path.display(), e).into());
+        }
     }
 
     Ok(())
 }
 
-#[derive(Debug)]
-struct Stats {
-    downloaded: usize,
-    failed: usize,
-    total_size: u64,
+fn write_summary_json(
+    writer: &mut impl Write,
+    summary: &Summary,
+    pretty: bool,
+) -> std::io::Result<()> {
+    if pretty {
+        serde_json::to_writer_pretty(&mut *writer, summary)?;
+    } else {
+        serde_json::to_writer(&mut *writer, summary)?;
+    }
+    writeln!(writer)
+}
+
+fn print_summary(summary: &Summary) {
+    println!();
+    println!("Results:");
+    println!("Downloaded: {}", summary.downloaded);
+    if summary.stalled > 0 {
+        println!("Failed: {} ({} stalled)", summary.failed, summary.stalled);
+    } else {
+        println!("Failed: {}", summary.failed);
+    }
+    if summary.mirror_failed > 0 {
+        println!("Mirror push failed: {}", summary.mirror_failed);
+    }
+    println!("Total size: {}", format_size(summary.total_size));
+    println!("Time: {:.2}s", summary.elapsed_secs);
+    if summary.downloaded > 0 {
+        println!(
+            "Speed: {}",
+            format_rate(summary.total_size, summary.elapsed_secs)
+        );
+    }
+    if summary.files_skipped > 0 {
+        println!("Skipped files: {}", summary.files_skipped);
+    }
+    if summary.dedupe_files_linked > 0 {
+        println!(
+            "Dedupe saved: {} ({} files linked)",
+            format_size(summary.dedupe_bytes_saved),
+            summary.dedupe_files_linked
+        );
+    }
+    if summary.archive_bytes_streamed > 0 {
+        println!(
+            "Streamed: {} extracted without a temporary archive ({} of disk IO avoided)",
+            format_size(summary.archive_bytes_streamed),
+            format_size(summary.archive_bytes_streamed * 2)
+        );
+    }
+    if summary.duplicate_archives > 0 {
+        println!(
+            "Duplicate archives: {} linked instead of extracted",
+            summary.duplicate_archives
+        );
+    }
+
+    if !summary.languages.is_empty() {
+        println!();
+        println!("By language:");
+        for lang in &summary.languages {
+            println!(
+                "  {}: {} repos, {}",
+                lang.language,
+                lang.repos,
+                format_size(lang.bytes)
+            );
+        }
+    }
+
+    if !summary.language_bytes.is_empty() {
+        let total: u64 = summary.language_bytes.iter().map(|l| l.bytes).sum();
+        println!();
+        println!("Language breakdown (from GitHub):");
+        for lang in &summary.language_bytes {
+            println!(
+                "  {}: {:.1}% in {} repos",
+                lang.language,
+                lang.bytes as f64 * 100.0 / total.max(1) as f64,
+                lang.repos
+            );
+        }
+    }
+
+    if !summary.largest.is_empty() {
+        println!();
+        println!("Largest repositories:");
+        for repo in &summary.largest {
+            println!("  {} ({})", repo.name, format_size(repo.bytes));
+        }
+    }
+
+    if let Some(limit) = summary.flag_depth
+        && !summary.deep.is_empty()
+    {
+        println!();
+        println!(
+            "Deeper than {} levels (vendored or generated content?):",
+            limit
+        );
+        for repo in &summary.deep {
+            println!("  {} ({} levels)", repo.name, repo.depth);
+        }
+    }
+}
+
+async fn explain(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let user = matches.get_one::<String>("user").unwrap();
+    let repo_name = matches.get_one::<String>("repo").unwrap();
+    let mut filters = parse_filters(matches);
+
+    let scraper = Scraper::new(base_config(matches)?)?;
+    if let Some(url) = matches.get_one::<String>("repo-blocklist-url") {
+        filters.blocklist = scraper.load_blocklist(url).await;
+    }
+    let repo = scraper.fetch_repo(user, repo_name).await?;
+
+    println!("Repository: {}/{}", user, repo.name);
+    println!(
+        "Language: {}",
+        repo.language.as_deref().unwrap_or("none reported")
+    );
+    println!("Stars: {}", repo.stars);
+    println!("Size: {} KB", repo.size);
+    println!("Fork: {}", if repo.is_fork { "yes" } else { "no" });
+    println!();
+
+    match filters.exclusion_reason(&repo) {
+        Some(reason) => println!("excluded: {}", reason),
+        None => println!("included: passes all filters"),
+    }
+
+    Ok(())
+}
+
+async fn doctor(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let token = matches.get_one::<String>("token");
+    let dir = Path::new(matches.get_one::<String>("dir").unwrap());
+
+    let scraper = Scraper::new(base_config(matches)?)?;
+
+    let mut failures = 0;
+    let mut report = |name: &str, result: Result<String, String>| match result {
+        Ok(detail) => println!("[PASS] {}: {}", name, detail),
+        Err(detail) => {
+            failures += 1;
+            println!("[FAIL] {}: {}", name, detail);
+        }
+    };
+
+    report("Proxy", check_proxy());
+    report("API connectivity", scraper.check_connectivity().await);
+
+    if token.is_some() {
+        report("Token", scraper.check_token().await);
+    } else {
+        println!("[SKIP] Token: none provided (60 requests/hour limit applies)");
+    }
+
+    report(
+        "Rate limit",
+        scraper.rate_limit().await.and_then(|rate| {
+            if rate.remaining > 0 {
+                Ok(format!(
+                    "{}/{} requests remaining",
+                    rate.remaining, rate.limit
+                ))
+            } else {
+                Err(format!(
+                    "exhausted, resets in {} minutes",
+                    seconds_until(rate.reset) / 60
+                ))
+            }
+        }),
+    );
+
+    report("Output directory", check_writable(dir));
+
+    println!();
+    if failures > 0 {
+        println!("{} check(s) failed", failures);
+        std::process::exit(1);
+    }
+    println!("All checks passed");
+
+    Ok(())
+}
+
+/// Mirrors reqwest's environment-based proxy selection for the API host.
+fn check_proxy() -> Result<String, String> {
+    let lookup = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+    };
+
+    let proxy = match lookup(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]) {
+        Some(proxy) => proxy,
+        None => return Ok("direct connection (no proxy configured)".to_string()),
+    };
+
+    if let Some(no_proxy) = lookup(&["NO_PROXY", "no_proxy"]) {
+        let bypassed = no_proxy.split(',').map(str::trim).any(|entry| {
+            let domain = entry.trim_start_matches('.');
+            entry == "*"
+                || domain == "api.github.com"
+                || "api.github.com".ends_with(&format!(".{}", domain))
+        });
+        if bypassed {
+            return Ok(format!(
+                "direct connection ({} bypassed by NO_PROXY)",
+                proxy
+            ));
+        }
+    }
+
+    reqwest::Proxy::https(&proxy)
+        .map(|_| format!("via {}", proxy))
+        .map_err(|e| format!("invalid proxy URL {}: {}", proxy, e))
+}
+
+fn check_writable(dir: &Path) -> Result<String, String> {
+    if !dir.is_dir() {
+        return Err(format!(
+            "{} does not exist or is not a directory",
+            dir.display()
+        ));
+    }
+
+    let probe = dir.join(".magnet-doctor");
+    fs::write(&probe, b"magnet")
+        .and_then(|_| fs::remove_file(&probe))
+        .map(|_| format!("{} is writable", dir.display()))
+        .map_err(|e| format!("cannot write to {}: {}", dir.display(), e))
+}
+
+fn seconds_until(epoch_secs: u64) -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    epoch_secs.saturating_sub(now)
+}
+
+/// Connection settings shared by every command; command-specific options are
+/// layered on top with struct update syntax.
+fn base_config(matches: &ArgMatches) -> Result<Config, String> {
+    // An explicit --token (or GITHUB_TOKEN) wins over the gh CLI.
+    let token = match matches.get_one::<String>("token") {
+        Some(token) => Some(token.clone()),
+        None if matches.get_flag("auth-from-gh-cli") => Some(gh_cli_token()?),
+        None => None,
+    };
+
+    Ok(Config {
+        token,
+        ca_bundle: matches.get_one::<PathBuf>("ca-bundle").cloned(),
+        insecure: matches.get_flag("danger-insecure"),
+        tls_backend: matches.get_one::<String>("tls-backend").map(|backend| {
+            match backend.as_str() {
+                "rustls" => TlsBackend::Rustls,
+                _ => TlsBackend::Native,
+            }
+        }),
+        wait_for_rate_limit: matches
+            .get_flag("wait-for-rate-limit")
+            .then(|| Duration::from_secs(*matches.get_one::<u64>("max-wait").unwrap())),
+        ..Default::default()
+    })
+}
+
+/// How `download` fetches each repository, chosen by `--mode`.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum DownloadMode {
+    /// Extract the branch or tag zip archive.
+    #[default]
+    Archive,
+    /// Bare mirror clone with the full history.
+    Clone,
+    /// Clone when history is asked for or no archive can be had, archive
+    /// otherwise; see `Scraper::prefers_clone`.
+    Auto,
+}
+
+/// How the default branch's archive is found, chosen by `--branch-strategy`.
+/// Each step up costs requests for a branch that is more surely right.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum BranchStrategy {
+    /// Use the listing's `default_branch`; if its archive fails, try the
+    /// repository's other branches.
+    #[default]
+    Default,
+    /// Use the listing's `default_branch` and nothing else: one request.
+    Metadata,
+    /// Confirm `default_branch` with the branches endpoint first, going
+    /// straight to the other branches when it is gone, then as `Default`.
+    Api,
+}
+
+const METADATA_CACHE_VERSION: u32 = 1;
+
+/// `--metadata-cache` file: the filtered repository list of a
+/// `--prefetch-metadata-only` run, as the API returned it plus each
+/// repository's head commit, so a later run can go straight to downloading.
+#[derive(Deserialize, Serialize)]
+struct MetadataCache {
+    version: u32,
+    /// Owner, or owners of a `download` list, it was listed for.
+    owner: String,
+    /// Unix time of the listing.
+    fetched_at: u64,
+    repos: Vec<RepoInfo>,
+}
+
+impl MetadataCache {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read metadata cache {}: {}", path.display(), e))?;
+        let cache: Self = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid metadata cache {}: {}", path.display(), e))?;
+        if cache.version != METADATA_CACHE_VERSION {
+            return Err(format!(
+                "Metadata cache {} has version {}, expected {}; prefetch it again",
+                path.display(),
+                cache.version,
+                METADATA_CACHE_VERSION
+            ));
+        }
+        Ok(cache)
+    }
+
+    /// Written to a temporary file and renamed, so a run reading it never
+    /// sees half a list.
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let written = fs::File::create(&tmp).and_then(|mut file| {
+            serde_json::to_writer(&mut file, self)?;
+            writeln!(file)
+        });
+        match written.and_then(|()| fs::rename(&tmp, path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                fs::remove_file(&tmp).ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// The cached repositories `source` covers, with their head commits
+    /// handed to the scraper. Listed repositories missing from the cache
+    /// are looked up through the API as usual.
+    async fn repos_for(self, scraper: &Scraper, source: &RepoSource) -> Vec<RepoInfo> {
+        let repos: Vec<RepoInfo> = match source {
+            RepoSource::Owner(username) => self
+                .repos
+                .into_iter()
+                .filter(|repo| repo.owner().eq_ignore_ascii_case(username))
+                .collect(),
+            RepoSource::List(entries) => {
+                let mut found = Vec::new();
+                let mut missing = Vec::new();
+                for (owner, name) in entries {
+                    let full_name = format!("{}/{}", owner, name);
+                    match self
+                        .repos
+                        .iter()
+                        .find(|repo| repo.full_name.eq_ignore_ascii_case(&full_name))
+                    {
+                        Some(repo) => found.push(repo.clone()),
+                        None => missing.push((owner.clone(), name.clone())),
+                    }
+                }
+                found.extend(scraper.fetch_listed_repos(&missing).await);
+                found
+            }
+        };
+        let mut head_shas = scraper.head_shas.lock().unwrap();
+        for repo in &repos {
+            if let Some(sha) = &repo.head_sha {
+                head_shas.insert(
+                    format!("{}@{}", repo.full_name, repo.default_branch),
+                    sha.clone(),
+                );
+            }
+        }
+        drop(head_shas);
+        repos
+    }
+}
+
+const LOCKFILE_VERSION: u32 = 1;
+
+/// `--pin-versions-file` lock: the commit every downloaded repository was
+/// extracted from, which `--from-lock` downloads again to rebuild the same
+/// snapshot after the repositories have moved on.
+#[derive(Deserialize, Serialize)]
+struct Lockfile {
+    version: u32,
+    /// Owner, or owners of a `download` list, it was written for.
+    owner: String,
+    /// Unix time it was written.
+    created_at: u64,
+    repos: Vec<LockedRepo>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct LockedRepo {
+    full_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    commit: String,
+}
+
+impl LockedRepo {
+    /// Matched by id first, so a renamed repository still finds its pin.
+    fn matches(&self, repo: &RepoInfo) -> bool {
+        match self.id {
+            Some(id) if repo.id != 0 => id == repo.id,
+            _ => self.full_name.eq_ignore_ascii_case(&repo.full_name),
+        }
+    }
+}
+
+impl Lockfile {
+    /// Pins the downloaded records with a known commit: directories
+    /// extracted from an archive. Clones, `--branches` trees and
+    /// repositories downloaded before commits were recorded have none.
+    fn pin(owner: &str, records: &[RepoRecord]) -> Self {
+        let mut repos: Vec<LockedRepo> = records
+            .iter()
+            .filter(|r| r.status == RepoStatus::Downloaded)
+            .filter_map(|r| {
+                Some(LockedRepo {
+                    full_name: r.full_name.clone(),
+                    id: r.id,
+                    commit: r.commit.clone()?,
+                })
+            })
+            .collect();
+        repos.sort_by(|a, b| a.full_name.cmp(&b.full_name));
+        Self {
+            version: LOCKFILE_VERSION,
+            owner: owner.to_string(),
+            created_at: unix_now(),
+            repos,
+        }
+    }
+
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read lock file {}: {}", path.display(), e))?;
+        let lock: Self = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid lock file {}: {}", path.display(), e))?;
+        if lock.version != LOCKFILE_VERSION {
+            return Err(format!(
+                "Lock file {} has version {}, expected {}",
+                path.display(),
+                lock.version,
+                LOCKFILE_VERSION
+            ));
+        }
+        Ok(lock)
+    }
+
+    /// Written like the metadata cache, via a temporary file and rename.
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = PathBuf::from(format!("{}.tmp", path.to_string_lossy()));
+        let written = fs::File::create(&tmp).and_then(|mut file| {
+            serde_json::to_writer_pretty(&mut file, self)?;
+            writeln!(file)
+        });
+        match written.and_then(|()| fs::rename(&tmp, path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                fs::remove_file(&tmp).ok();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The commit `repo` is pinned at in `locked`, if any.
+fn pinned_commit<'a>(locked: Option<&'a [LockedRepo]>, repo: &RepoInfo) -> Option<&'a str> {
+    locked?
+        .iter()
+        .find(|entry| entry.matches(repo))
+        .map(|entry| entry.commit.as_str())
+}
+
+/// Under `--from-lock`, keeps the listed repositories that are pinned, so
+/// ones created since the lock was written stay out of the snapshot, and
+/// warns about each pinned repository of `source` the listing no longer
+/// has: those cannot be reproduced.
+fn restrict_to_lock(
+    repos: Vec<RepoInfo>,
+    locked: &[LockedRepo],
+    source: &RepoSource,
+    quiet: bool,
+) -> Vec<RepoInfo> {
+    let in_source = |entry: &LockedRepo| match source {
+        RepoSource::Owner(username) => entry
+            .full_name
+            .split_once('/')
+            .is_some_and(|(owner, _)| owner.eq_ignore_ascii_case(username)),
+        RepoSource::List(entries) => entries.iter().any(|(owner, name)| {
+            entry
+                .full_name
+                .eq_ignore_ascii_case(&format!("{}/{}", owner, name))
+        }),
+    };
+    for entry in locked.iter().filter(|entry| in_source(entry)) {
+        if !repos.iter().any(|repo| entry.matches(repo)) {
+            eprintln!(
+                "WARNING: {} is pinned in the lock file but no longer exists; it cannot be reproduced",
+                entry.full_name
+            );
+        }
+    }
+
+    let listed = repos.len();
+    let repos: Vec<RepoInfo> = repos
+        .into_iter()
+        .filter(|repo| locked.iter().any(|entry| entry.matches(repo)))
+        .collect();
+    if repos.len() < listed {
+        status!(
+            quiet,
+            "Leaving out {} repositories not in the lock file",
+            listed - repos.len()
+        );
+    }
+    repos
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// `--ref-in-dirname` directories, by the plain `<root>/<name>` path of
+/// their repository. Seeded from the manifests' `dir` fields, so a
+/// repository extracted by an earlier run is found under its richer name
+/// and not downloaded again.
+#[derive(Default)]
+struct RefDirs {
+    dirs: std::sync::Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+impl RefDirs {
+    fn load(&self, root: &Path, records: &[RepoRecord]) {
+        let mut dirs = self.dirs.lock().unwrap();
+        for record in records {
+            if let Some(dir) = &record.dir {
+                dirs.insert(root.join(&record.name), root.join(dir));
+            }
+        }
+    }
+
+    fn insert(&self, plain: &Path, named: &Path) {
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(plain.to_path_buf(), named.to_path_buf());
+    }
+
+    /// Where the repository at `plain` is: its named directory if that
+    /// still exists, else `plain` itself.
+    fn resolve(&self, plain: &Path) -> PathBuf {
+        match self.dirs.lock().unwrap().get(plain) {
+            Some(named) if named.is_dir() => named.clone(),
+            _ => plain.to_path_buf(),
+        }
+    }
+}
+
+/// `--ref-in-dirname` name for the repository extracted to `repo_path`:
+/// `<name>@<ref>-<short sha>`, with the ref taken from GitHub's
+/// `<name>-<ref>/` wrapper directory and the commit from the zip comment.
+/// `None` if the archive carries neither in the expected form.
+fn ref_dir_name(zip_path: &Path, repo_path: &Path) -> Option<String> {
+    let name = repo_path.file_name()?.to_str()?;
+    let archive = ZipArchive::new(fs::File::open(zip_path).ok()?).ok()?;
+    let sha = archive_commit(&archive)?;
+    let wrapper = archive.file_names().next()?.split('/').next()?;
+    let git_ref = wrapper.strip_prefix(name)?.strip_prefix('-')?;
+    if git_ref.is_empty() {
+        return None;
+    }
+    Some(if sha.starts_with(git_ref) {
+        format!("{}@{}", name, &sha[..7])
+    } else {
+        format!("{}@{}-{}", name, git_ref, &sha[..7])
+    })
+}
+
+/// Commit a GitHub archive was made from, which it carries as its zip
+/// comment.
+fn archive_commit(archive: &ZipArchive<fs::File>) -> Option<&str> {
+    let sha = std::str::from_utf8(archive.comment()).ok()?.trim();
+    (sha.len() == 40 && sha.bytes().all(|b| b.is_ascii_hexdigit())).then_some(sha)
+}
+
+/// `--mirror-to` push outcome of one repository.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+struct MirrorPush {
+    remote: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A `git` invocation that never prompts, over HTTPS or SSH, and that
+/// authenticates GitHub HTTPS requests with the token, if any. The header is
+/// passed through `GIT_CONFIG_*` rather than the command line, which other
+/// users can read.
+fn git_command(token: Option<&str>) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new("git");
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+        command.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+    }
+    if let Some(token) = token {
+        let credentials = BASE64_STANDARD.encode(format!("x-access-token:{}", token));
+        command
+            .env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "http.https://github.com/.extraheader")
+            .env(
+                "GIT_CONFIG_VALUE_0",
+                format!("Authorization: Basic {}", credentials),
+            );
+    }
+    command
+}
+
+/// Whether `repo_path` holds a bare clone left by `--mode clone` or `auto`.
+fn is_bare_clone(repo_path: &Path) -> bool {
+    repo_path.join("HEAD").is_file() && repo_path.join("objects").is_dir()
+}
+
+/// Runs `command`, turning a failure into git's own error message.
+async fn run_git(mut command: tokio::process::Command) -> Result<(), String> {
+    let output = command.output().await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => "git is not installed or not on PATH".to_string(),
+        _ => format!("could not run git: {}", e),
+    })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Pushes the bare clone at `repo_path` to the `--mirror-to` remote with
+/// `git push --mirror`. A local path remote that does not exist yet is
+/// created as a bare repository first; remote servers must create it on
+/// push themselves.
+async fn mirror_push(
+    repo: &RepoInfo,
+    repo_path: &Path,
+    template: &str,
+    token: Option<&str>,
+    log: &RepoLog,
+) -> MirrorPush {
+    let remote = template
+        .replace("{owner}", repo.owner())
+        .replace("{name}", &repo.name);
+    let push = async {
+        let local = remote.strip_prefix("file://").unwrap_or(&remote);
+        if !remote.contains(':') || remote.starts_with("file://") {
+            let local = Path::new(local);
+            if !local.exists() {
+                log.event(format!("creating bare repository {}", local.display()));
+                let mut command = git_command(None);
+                command.args(["init", "--bare", "--quiet"]).arg(local);
+                run_git(command).await?;
+            }
+        }
+
+        log.event(format!("git push --mirror {}", remote));
+        let mut command = git_command(token);
+        command
+            .arg("-C")
+            .arg(repo_path)
+            .args(["push", "--mirror", "--quiet", &remote]);
+        run_git(command).await
+    };
+
+    let error = push.await.err();
+    if let Some(e) = &error {
+        log.event(format!("mirror push failed: {}", e));
+    }
+    MirrorPush { remote, error }
+}
+
+/// Asks the GitHub CLI for its stored token, for `--auth-from-gh-cli`.
+fn gh_cli_token() -> Result<String, String> {
+    let output = std::process::Command::new("gh")
+        .args(["auth", "token"])
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                "--auth-from-gh-cli: the gh CLI is not installed or not on PATH".to_string()
+            }
+            _ => format!("--auth-from-gh-cli: could not run gh: {}", e),
+        })?;
+
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || token.is_empty() {
+        return Err(format!(
+            "--auth-from-gh-cli: gh has no token (run `gh auth login` first): {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(token)
+}
+
+fn parse_filters(matches: &ArgMatches) -> Filters {
+    let regex = matches
+        .get_one::<String>("regex")
+        .map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                eprintln!("Invalid regex: {}", e);
+                std::process::exit(1);
+            }
+        });
+
+    Filters {
+        language: matches.get_one::<String>("language").cloned(),
+        min_stars: *matches.get_one::<u32>("min-stars").unwrap_or(&0),
+        max_size: matches.get_one::<u32>("max-size").copied(),
+        only_original: matches.get_flag("only-original"),
+        regex,
+        name_contains: matches
+            .get_many::<String>("name-contains")
+            .unwrap_or_default()
+            .map(|s| s.to_lowercase())
+            .collect(),
+        name_contains_all: matches
+            .get_one::<String>("name-contains-match")
+            .is_some_and(|mode| mode == "all"),
+        blocklist: Vec::new(),
+    }
+}
+
+struct ProgressTracker {
+    /// Repositories in the pass so far; grows while `--stream-listing`
+    /// is still paging.
+    total: std::sync::atomic::AtomicUsize,
+    quiet: bool,
+    completed: Mutex<usize>,
+    throttle: Option<ProgressThrottle>,
+    records: Mutex<Vec<RepoRecord>>,
+    /// One manifest writer per destination directory.
+    manifests: Vec<mpsc::UnboundedSender<RepoRecord>>,
+    events: Option<Arc<EventBus>>,
+}
+
+impl ProgressTracker {
+    fn new(
+        total: usize,
+        quiet: bool,
+        progress_interval: Option<Duration>,
+        manifests: Vec<mpsc::UnboundedSender<RepoRecord>>,
+        events: Option<Arc<EventBus>>,
+    ) -> Self {
+        Self {
+            total: total.into(),
+            quiet,
+            completed: Mutex::new(0),
+            throttle: progress_interval.map(ProgressThrottle::new),
+            records: Mutex::new(Vec::new()),
+            manifests,
+            events,
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    fn add_total(&self, repos: usize) {
+        self.total.fetch_add(repos, Ordering::Relaxed);
+    }
+
+    /// Takes out a repository that will not be downloaded after all.
+    fn remove_from_total(&self) {
+        self.total.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn report_start(&self, repo: &RepoInfo) {
+        if let Some(events) = &self.events {
+            events.emit(Event::Started {
+                repo: &repo.full_name,
+                total: self.total(),
+            });
+        }
+    }
+
+    async fn report_completion(
+        &self,
+        repo: &RepoInfo,
+        destination: usize,
+        result: Result<Download, DownloadError>,
+    ) {
+        let mut completed = self.completed.lock().await;
+        *completed += 1;
+        let current = *completed;
+
+        match &result {
+            Ok(download) if let Some(throttle) = &self.throttle => {
+                throttle.add(download.bytes);
+            }
+            Err(_) if let Some(throttle) = &self.throttle => throttle.fail(),
+            Ok(download) => {
+                let name = match &download.tag {
+                    Some(tag) => format!("{} @ {}", repo.name, tag),
+                    None if !download.branches.is_empty() => {
+                        format!("{} [{}]", repo.name, download.branches.join(", "))
+                    }
+                    None => repo.name.clone(),
+                };
+                status!(
+                    self.quiet,
+                    "[{}/{}] {} ({})",
+                    current,
+                    self.total(),
+                    name,
+                    format_size(download.bytes)
+                )
+            }
+            Err(e) => status!(
+                self.quiet,
+                "[{}/{}] {} FAILED: {}",
+                current,
+                self.total(),
+                repo.name,
+                e
+            ),
+        }
+        if let Ok(Download {
+            mirror: Some(mirror),
+            ..
+        }) = &result
+            && let Some(e) = &mirror.error
+        {
+            status!(
+                self.quiet,
+                "WARNING: Mirror push of {} to {} failed: {}",
+                repo.name,
+                mirror.remote,
+                e
+            );
+        }
+
+        let record = RepoRecord::new(repo, result);
+        if let Some(events) = &self.events {
+            let event = if record.status == RepoStatus::Downloaded {
+                Event::Completed {
+                    repo: &record,
+                    current,
+                    total: self.total(),
+                }
+            } else {
+                Event::Failed {
+                    repo: &record,
+                    current,
+                    total: self.total(),
+                }
+            };
+            events.emit(event);
+        }
+        self.manifests[destination].send(record.clone()).ok();
+        self.records.lock().await.push(record);
+
+        if let Some(throttle) = &self.throttle
+            && let Some(line) = throttle.line(current, self.total())
+        {
+            status!(self.quiet, "{}", line);
+        }
+    }
+
+    async fn records(&self) -> Vec<RepoRecord> {
+        self.records.lock().await.clone()
+    }
+
+    /// Prints the `--progress-interval` line every interval, so it keeps
+    /// coming while long downloads are the only ones left.
+    async fn tick(&self) {
+        let Some(throttle) = &self.throttle else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(throttle.interval).await;
+            let current = *self.completed.lock().await;
+            if let Some(line) = throttle.line(current, self.total()) {
+                status!(self.quiet, "{}", line);
+            }
+        }
+    }
+}
+
+/// Aggregate progress printed under `--progress-interval` in place of the
+/// per-repository lines, at most once per interval and once at the end.
+struct ProgressThrottle {
+    interval: Duration,
+    started: Instant,
+    state: std::sync::Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    last_printed: Instant,
+    bytes: u64,
+    failed: usize,
+}
+
+impl ProgressThrottle {
+    fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            started: now,
+            state: std::sync::Mutex::new(ThrottleState {
+                last_printed: now,
+                bytes: 0,
+                failed: 0,
+            }),
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        self.state.lock().unwrap().bytes += bytes;
+    }
+
+    fn fail(&self) {
+        self.state.lock().unwrap().failed += 1;
+    }
+
+    /// The progress line, if one is due after `current` completions.
+    fn line(&self, current: usize, total: usize) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        if current < total && state.last_printed.elapsed() < self.interval {
+            return None;
+        }
+        state.last_printed = Instant::now();
+
+        let mut line = format!(
+            "{}/{} done, {}, {}",
+            current,
+            total,
+            format_size(state.bytes),
+            format_rate(state.bytes, self.started.elapsed().as_secs_f64())
+        );
+        if state.failed > 0 {
+            line.push_str(&format!(", {} failed", state.failed));
+        }
+        Some(line)
+    }
+}
+
+/// One line of the `--events-socket` stream, tagged by its `event` field.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event<'a> {
+    Started {
+        repo: &'a str,
+        total: usize,
+    },
+    /// Archive bytes received so far, sent at each resume checkpoint.
+    Progress {
+        repo: &'a str,
+        bytes: u64,
+    },
+    Completed {
+        repo: &'a RepoRecord,
+        current: usize,
+        total: usize,
+    },
+    Failed {
+        repo: &'a RepoRecord,
+        current: usize,
+        total: usize,
+    },
+    Summary(&'a Summary),
+}
+
+/// What a client attaching mid-run is told before live events start.
+#[derive(Clone, Default, Serialize)]
+struct EventState {
+    total: usize,
+    completed: usize,
+    active: Vec<String>,
+}
+
+/// Fans JSON-line events out to every client of the `--events-socket`.
+/// Clients come and go freely; with none attached, events are dropped.
+struct EventBus {
+    tx: tokio::sync::broadcast::Sender<String>,
+    state: std::sync::Mutex<EventState>,
+}
+
+impl EventBus {
+    #[cfg(unix)]
+    fn serve(path: &Path) -> Result<Arc<Self>, String> {
+        use tokio::io::AsyncWriteExt;
+
+        // A socket file left by an earlier run would make bind fail.
+        if path.exists() {
+            fs::remove_file(path)
+                .map_err(|e| format!("Cannot replace {}: {}", path.display(), e))?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .map_err(|e| format!("Cannot listen on {}: {}", path.display(), e))?;
+
+        let (tx, _) = tokio::sync::broadcast::channel(1024);
+        let bus = Arc::new(Self {
+            tx,
+            state: std::sync::Mutex::new(EventState::default()),
+        });
+
+        let server = bus.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // Subscribe before taking the snapshot so nothing falls in
+                // between; a client may see an event already reflected in it.
+                let mut rx = server.tx.subscribe();
+                let snapshot = server.snapshot();
+                tokio::spawn(async move {
+                    if stream.write_all(snapshot.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    loop {
+                        match rx.recv().await {
+                            Ok(line) => {
+                                if stream.write_all(line.as_bytes()).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(bus)
+    }
+
+    #[cfg(not(unix))]
+    fn serve(_path: &Path) -> Result<Arc<Self>, String> {
+        Err("--events-socket needs Unix domain sockets, which this platform lacks".to_string())
+    }
+
+    fn snapshot(&self) -> String {
+        #[derive(Serialize)]
+        struct Snapshot<'a> {
+            event: &'static str,
+            #[serde(flatten)]
+            state: &'a EventState,
+        }
+
+        let state = self.state.lock().unwrap();
+        let snapshot = Snapshot {
+            event: "state",
+            state: &state,
+        };
+        serde_json::to_string(&snapshot).unwrap_or_default() + "\n"
+    }
+
+    fn emit(&self, event: Event) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match &event {
+                Event::Started { repo, total } => {
+                    state.total = *total;
+                    state.active.push(repo.to_string());
+                }
+                Event::Completed { repo, current, .. } | Event::Failed { repo, current, .. } => {
+                    state.completed = *current;
+                    state.active.retain(|name| *name != repo.full_name);
+                }
+                Event::Summary(_) => {
+                    state.completed = 0;
+                    state.active.clear();
+                }
+                Event::Progress { .. } => {}
+            }
+        }
+
+        if let Ok(line) = serde_json::to_string(&event) {
+            // Fails only when no client is attached.
+            self.tx.send(line + "\n").ok();
+        }
+    }
+}
+
+/// Persisted outcome of a run, written to `.magnet-manifest.json` (or its
+/// `--output-manifest-format` counterpart) in the output directory so
+/// summaries can be regenerated without the network.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    owner: String,
+    elapsed_secs: f64,
+    files_skipped: u64,
+    dedupe_bytes_saved: u64,
+    dedupe_files_linked: u64,
+    /// `--stream-tarball` bytes; absent from manifests of earlier runs.
+    #[serde(default)]
+    archive_bytes_streamed: u64,
+    repos: Vec<RepoRecord>,
+}
+
+impl Manifest {
+    /// Reads the manifest in whichever format it was saved; if several
+    /// exist, the most recently written wins.
+    fn load(dir: &Path) -> Result<Self, String> {
+        let format = ManifestFormat::newest(dir, |format| format.file_name().to_string())
+            .unwrap_or_default();
+        let path = dir.join(format.file_name());
+        let data = fs::read_to_string(&path)
+            .map_err(|e| format!("Cannot read manifest {}: {}", path.display(), e))?;
+        format
+            .decode(&data)
+            .map_err(|e| format!("Invalid manifest {}: {}", path.display(), e))
+    }
+
+    /// Writes the manifest via a temporary file and rename so readers never
+    /// observe a half-written file, even if the process dies mid-write. A
+    /// manifest an earlier run left in another format is removed, so the
+    /// directory holds exactly one.
+    fn save(&self, dir: &Path, format: ManifestFormat) -> Result<(), String> {
+        let data = format.encode(self)?;
+        let path = dir.join(format.file_name());
+        let tmp = dir.join(format!("{}.tmp", format.file_name()));
+        fs::write(&tmp, data).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &path).map_err(|e| e.to_string())?;
+        for other in MANIFEST_FORMATS {
+            if other != format {
+                fs::remove_file(dir.join(other.file_name())).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the record for the same repository, or appends a new one.
+    /// A failure extends the previous record's failure streak.
+    fn upsert(&mut self, mut record: RepoRecord) {
+        match self
+            .repos
+            .iter_mut()
+            .find(|r| r.full_name == record.full_name)
+        {
+            Some(existing) => {
+                if record.status != RepoStatus::Downloaded {
+                    record.consecutive_failures = existing.consecutive_failures + 1;
+                } else if record.commit.is_none() && existing.status == RepoStatus::Downloaded {
+                    // Found on disk, so still the tree of the earlier download.
+                    record.commit = existing.commit.take();
+                }
+                *existing = record;
+            }
+            None => self.repos.push(record),
+        }
+    }
+}
+
+/// Single writer task that owns the on-disk manifest. Download tasks send
+/// records over a channel as they complete, and the writer rewrites the
+/// manifest after each batch, so a crash loses at most the repositories that
+/// were still in flight. Records from earlier runs are kept and updated in
+/// place.
+struct ManifestWriter {
+    tx: mpsc::UnboundedSender<RepoRecord>,
+    task: tokio::task::JoinHandle<Manifest>,
+    dir: PathBuf,
+    format: ManifestFormat,
+}
+
+impl ManifestWriter {
+    fn spawn(dir: PathBuf, owner: &str, format: ManifestFormat) -> Self {
+        let mut manifest = Manifest::load(&dir)
+            .ok()
+            .filter(|m| m.version == MANIFEST_VERSION)
+            .unwrap_or_else(|| Manifest {
+                version: MANIFEST_VERSION,
+                owner: owner.to_string(),
+                elapsed_secs: 0.0,
+                files_skipped: 0,
+                dedupe_bytes_saved: 0,
+                dedupe_files_linked: 0,
+                archive_bytes_streamed: 0,
+                repos: Vec::new(),
+            });
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<RepoRecord>();
+        let task_dir = dir.clone();
+        let task = tokio::spawn(async move {
+            let mut warned = false;
+            while let Some(record) = rx.recv().await {
+                manifest.upsert(record);
+                while let Ok(record) = rx.try_recv() {
+                    manifest.upsert(record);
+                }
+
+                if let Err(e) = manifest.save(&task_dir, format)
+                    && !warned
+                {
+                    eprintln!("WARNING: Could not update manifest: {}", e);
+                    warned = true;
+                }
+            }
+            manifest
+        });
+
+        Self {
+            tx,
+            task,
+            dir,
+            format,
+        }
+    }
+
+    fn sender(&self) -> mpsc::UnboundedSender<RepoRecord> {
+        self.tx.clone()
+    }
+
+    /// Waits for pending records to be written, then stores the run totals
+    /// and returns the saved manifest. All senders (held by the progress
+    /// tracker) must be dropped first.
+    async fn finish(self, run: &Manifest) -> Result<Manifest, String> {
+        drop(self.tx);
+        let mut manifest = self.task.await.map_err(|e| e.to_string())?;
+
+        manifest.elapsed_secs = run.elapsed_secs;
+        manifest.files_skipped = run.files_skipped;
+        manifest.dedupe_bytes_saved = run.dedupe_bytes_saved;
+        manifest.dedupe_files_linked = run.dedupe_files_linked;
+        manifest.archive_bytes_streamed = run.archive_bytes_streamed;
+        manifest.save(&self.dir, self.format)?;
+        Ok(manifest)
+    }
+}
+
+/// `account.json` under `--account-metadata`: repository settings that an
+/// archive does not carry, for restoring an account elsewhere. Entries from
+/// earlier runs are kept and replaced as their repositories come up again.
+#[derive(Serialize, Deserialize)]
+struct AccountMetadata {
+    owner: String,
+    repos: Vec<AccountRepo>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct AccountRepo {
+    full_name: String,
+    html_url: String,
+    default_branch: String,
+    description: Option<String>,
+    homepage: Option<String>,
+    topics: Vec<String>,
+    visibility: Option<String>,
+    fork: bool,
+    /// The default branch's protection rules as the API returns them.
+    /// Absent when the token may not read them (they need admin access) or
+    /// the branch is unprotected, which GitHub does not tell apart for
+    /// non-admins. Saved without its nulls, which TOML cannot hold.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_without_nulls"
+    )]
+    branch_protection: Option<serde_json::Value>,
+}
+
+/// Serializes an API payload with every `null` dropped from its objects
+/// and arrays. GitHub uses them for fields that are not set, e.g. a status
+/// check's `app_id`, so nothing is lost, but TOML has no null and would
+/// refuse the whole file.
+fn serialize_without_nulls<S: serde::Serializer>(
+    value: &Option<serde_json::Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    fn strip(value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(fields) => fields
+                .iter()
+                .filter(|(_, field)| !field.is_null())
+                .map(|(key, field)| (key.clone(), strip(field)))
+                .collect(),
+            serde_json::Value::Array(items) => items
+                .iter()
+                .filter(|item| !item.is_null())
+                .map(strip)
+                .collect(),
+            other => other.clone(),
+        }
+    }
+
+    value
+        .as_ref()
+        .filter(|value| !value.is_null())
+        .map(strip)
+        .serialize(serializer)
+}
+
+impl AccountMetadata {
+    fn file_name(format: ManifestFormat) -> String {
+        format!("{}.{}", ACCOUNT_METADATA_STEM, format.extension())
+    }
+
+    /// Merges `repos` into the file, which is read in whichever format it
+    /// was last saved and rewritten in `format`, like the manifest.
+    fn update(
+        dir: &Path,
+        owner: &str,
+        repos: Vec<&AccountRepo>,
+        format: ManifestFormat,
+    ) -> Result<(), String> {
+        let previous = ManifestFormat::newest(dir, Self::file_name).and_then(|previous| {
+            let data = fs::read_to_string(dir.join(Self::file_name(previous))).ok()?;
+            previous.decode::<AccountMetadata>(&data).ok()
+        });
+        let mut metadata = previous.unwrap_or_else(|| AccountMetadata {
+            owner: owner.to_string(),
+            repos: Vec::new(),
+        });
+        for repo in repos {
+            match metadata
+                .repos
+                .iter_mut()
+                .find(|r| r.full_name == repo.full_name)
+            {
+                Some(existing) => *existing = repo.clone(),
+                None => metadata.repos.push(repo.clone()),
+            }
+        }
+        metadata.repos.sort_by(|a, b| a.full_name.cmp(&b.full_name));
+
+        let data = format.encode(&metadata)?;
+        let path = dir.join(Self::file_name(format));
+        let tmp = dir.join(format!("{}.tmp", Self::file_name(format)));
+        fs::write(&tmp, data).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &path).map_err(|e| e.to_string())?;
+        for other in MANIFEST_FORMATS {
+            if other != format {
+                fs::remove_file(dir.join(Self::file_name(other))).ok();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// On-disk format of the manifest and the `account` sidecar
+/// (`--output-manifest-format`). Every format serializes the same types
+/// with the same serde attributes. The lock file, metadata cache and
+/// duplicate cluster report are written to paths the user names and stay
+/// JSON.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ManifestFormat {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+}
+
+const MANIFEST_FORMATS: [ManifestFormat; 3] = [
+    ManifestFormat::Json,
+    ManifestFormat::Toml,
+    ManifestFormat::Yaml,
+];
+
+impl ManifestFormat {
+    fn file_name(self) -> &'static str {
+        match self {
+            ManifestFormat::Json => ".magnet-manifest.json",
+            ManifestFormat::Toml => ".magnet-manifest.toml",
+            ManifestFormat::Yaml => ".magnet-manifest.yaml",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ManifestFormat::Json => "json",
+            ManifestFormat::Toml => "toml",
+            ManifestFormat::Yaml => "yaml",
+        }
+    }
+
+    /// The format of whichever of the files `name` gives for each format in
+    /// `dir` was written last, if any exists.
+    fn newest(dir: &Path, name: impl Fn(ManifestFormat) -> String) -> Option<Self> {
+        MANIFEST_FORMATS
+            .into_iter()
+            .filter_map(|format| {
+                let modified = fs::metadata(dir.join(name(format)))
+                    .and_then(|m| m.modified())
+                    .ok()?;
+                Some((modified, format))
+            })
+            .max_by_key(|(modified, _)| *modified)
+            .map(|(_, format)| format)
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<String, String> {
+        match self {
+            ManifestFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+            ManifestFormat::Toml => toml::to_string(value).map_err(|e| e.to_string()),
+            ManifestFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn decode<T: serde::de::DeserializeOwned>(self, text: &str) -> Result<T, String> {
+        match self {
+            ManifestFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+            ManifestFormat::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+            ManifestFormat::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum RepoStatus {
+    Downloaded,
+    Failed,
+    /// Gave up after the transfer repeatedly stopped making progress.
+    Stalled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct RepoRecord {
+    name: String,
+    full_name: String,
+    /// GitHub's repository id, which survives renames; absent in manifests
+    /// written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    language: Option<String>,
+    /// Language assigned by `--language-detect` when the API reported none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detected_language: Option<String>,
+    /// Bytes per language reported by the API, under `--language-breakdown`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    language_bytes: BTreeMap<String, u64>,
+    /// Tag chosen by `--latest-tag`; absent when the default branch was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    /// Branch subdirectories downloaded by `--branches`/`--all-branches`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    branches: Vec<String>,
+    /// Grouped archive written by `--compact-small-repos`, instead of a
+    /// directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bundle: Option<String>,
+    /// Head commit signature of the downloaded ref, under
+    /// `--verify-signatures`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<CommitSignature>,
+    /// `--mirror-to` push, reported apart from the download: a failed push
+    /// leaves the repository downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mirror: Option<MirrorPush>,
+    /// `--mode auto` chose a bare clone over the archive.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    cloned: bool,
+    /// Repository with a byte-identical archive whose extraction this one
+    /// links to, under `--dedupe-archives`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<String>,
+    /// Directory holding the repository when it is not named after it, as
+    /// under `--ref-in-dirname` or `--rename-on-conflict`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir: Option<String>,
+    /// Repository that claimed this one's directory first in the pass, so
+    /// `--rename-on-conflict` gave it `dir` instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conflicts_with: Option<String>,
+    /// Most path components of any extracted entry below the repository
+    /// directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree_depth: Option<u32>,
+    /// Commit the extracted archive was made from, which
+    /// `--pin-versions-file` pins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+    stars: u32,
+    /// Size reported by the GitHub API, in KB.
+    size_kb: u32,
+    status: RepoStatus,
+    /// Bytes on disk after extraction.
+    bytes: u64,
+    error: Option<String>,
+    /// Failed runs in a row; reset by a successful download.
+    #[serde(default)]
+    consecutive_failures: u32,
+}
+
+impl RepoRecord {
+    fn new(repo: &RepoInfo, result: Result<Download, DownloadError>) -> Self {
+        let mut record = Self {
+            name: repo.name.clone(),
+            full_name: repo.full_name.clone(),
+            id: Some(repo.id).filter(|id| *id != 0),
+            language: repo.language.clone(),
+            detected_language: None,
+            language_bytes: BTreeMap::new(),
+            tag: None,
+            branches: Vec::new(),
+            bundle: None,
+            signature: None,
+            mirror: None,
+            cloned: false,
+            duplicate_of: None,
+            dir: None,
+            conflicts_with: None,
+            tree_depth: None,
+            commit: None,
+            stars: repo.stars,
+            size_kb: repo.size,
+            status: RepoStatus::Failed,
+            bytes: 0,
+            error: None,
+            consecutive_failures: 1,
+        };
+
+        match result {
+            Ok(download) => {
+                record.status = RepoStatus::Downloaded;
+                record.consecutive_failures = 0;
+                record.bytes = download.bytes;
+                record.detected_language = download.detected_language;
+                record.language_bytes = download.language_bytes;
+                record.tag = download.tag;
+                record.branches = download.branches;
+                record.bundle = download.bundle;
+                record.signature = download.signature;
+                record.mirror = download.mirror;
+                record.cloned = download.cloned;
+                record.duplicate_of = download.duplicate_of;
+                record.dir = download.dir;
+                record.conflicts_with = download.conflicts_with;
+                record.tree_depth = download.tree_depth;
+                record.commit = download.commit;
+            }
+            Err(DownloadError::Stalled(e)) => {
+                record.status = RepoStatus::Stalled;
+                record.error = Some(e);
+            }
+            Err(DownloadError::NotFound(e) | DownloadError::Failed(e)) => record.error = Some(e),
+        }
+
+        record
+    }
+
+    /// The API language, falling back to the locally detected one.
+    fn effective_language(&self) -> Option<&str> {
+        self.language
+            .as_deref()
+            .or(self.detected_language.as_deref())
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+struct LanguageTotal {
+    language: String,
+    repos: usize,
+    bytes: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct RepoSize {
+    name: String,
+    bytes: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct RepoDepth {
+    name: String,
+    depth: u32,
+}
+
+/// JSON Schema (draft 2020-12) of `Summary`, the `--json` and
+/// `--summary-json-to` output, printed by `magnet json-schema`. Derived
+/// from the serde types as they serialize, so fields, renames and the
+/// optionality of skipped fields follow the structs; doc comments become
+/// descriptions.
+fn summary_schema() -> serde_json::Value {
+    let generator = schemars::generate::SchemaSettings::draft2020_12()
+        .for_serialize()
+        .into_generator();
+    let mut schema = generator.into_root_schema_for::<Summary>();
+    schema.insert(
+        "$id".to_string(),
+        format!(
+            "https://github.com/naseridev/magnet/schema/summary-v{}.json",
+            MANIFEST_VERSION
+        )
+        .into(),
+    );
+    schema.insert("title".to_string(), "magnet summary".into());
+    schema.to_value()
+}
+
+/// `--since-id` report: how the matching repositories differ from the
+/// manifest's records. Records are matched by repository id when both sides
+/// have one, so a rename is not reported as a removal plus an addition, and
+/// by full name otherwise.
+#[derive(Serialize)]
+struct RepoDelta {
+    added: Vec<String>,
+    removed: Vec<String>,
+    renamed: Vec<RenamedRepo>,
+    resized: Vec<ResizedRepo>,
+}
+
+#[derive(Serialize)]
+struct RenamedRepo {
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize)]
+struct ResizedRepo {
+    full_name: String,
+    /// GitHub's reported size, in KB, as recorded and now.
+    from_kb: u32,
+    to_kb: u32,
+}
+
+impl RepoDelta {
+    fn between(previous: &[&RepoRecord], current: &[RepoInfo]) -> Self {
+        let mut delta = Self {
+            added: Vec::new(),
+            removed: Vec::new(),
+            renamed: Vec::new(),
+            resized: Vec::new(),
+        };
+        let mut matched = vec![false; current.len()];
+        for record in previous {
+            let by_id = record
+                .id
+                .and_then(|id| current.iter().position(|repo| repo.id == id));
+            let by_name = || {
+                current
+                    .iter()
+                    .position(|repo| repo.full_name == record.full_name)
+            };
+            let Some(index) = by_id.or_else(by_name) else {
+                delta.removed.push(record.full_name.clone());
+                continue;
+            };
+            matched[index] = true;
+            let repo = &current[index];
+            if repo.full_name != record.full_name {
+                delta.renamed.push(RenamedRepo {
+                    from: record.full_name.clone(),
+                    to: repo.full_name.clone(),
+                });
+            }
+            if repo.size != record.size_kb {
+                delta.resized.push(ResizedRepo {
+                    full_name: repo.full_name.clone(),
+                    from_kb: record.size_kb,
+                    to_kb: repo.size,
+                });
+            }
+        }
+        delta.added = current
+            .iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(repo, _)| repo.full_name.clone())
+            .collect();
+        delta
+    }
+
+    fn print(&self) {
+        if self.added.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && self.resized.is_empty()
+        {
+            println!("No changes since the last run");
+            return;
+        }
+        for name in &self.added {
+            println!("added: {}", name);
+        }
+        for name in &self.removed {
+            println!("removed: {}", name);
+        }
+        for rename in &self.renamed {
+            println!("renamed: {} -> {}", rename.from, rename.to);
+        }
+        for resize in &self.resized {
+            println!(
+                "resized: {} ({} -> {})",
+                resize.full_name,
+                format_size(u64::from(resize.from_kb) * 1024),
+                format_size(u64::from(resize.to_kb) * 1024)
+            );
+        }
+    }
+}
+
+/// Aggregates derived from a manifest; shared by live runs and `report`.
+#[derive(Serialize, JsonSchema)]
+struct Summary {
+    owner: String,
+    downloaded: usize,
+    failed: usize,
+    /// Subset of `failed` abandoned because the transfer stalled.
+    stalled: usize,
+    /// Downloaded repositories whose `--mirror-to` push failed.
+    mirror_failed: usize,
+    total_size: u64,
+    elapsed_secs: f64,
+    files_skipped: u64,
+    dedupe_bytes_saved: u64,
+    dedupe_files_linked: u64,
+    /// Archive bytes `--stream-tarball` extracted as they arrived; a zip
+    /// of the same size is written to disk and read back once more.
+    archive_bytes_streamed: u64,
+    /// Downloaded repositories linked to an identical archive's extraction
+    /// by `--dedupe-archives`.
+    duplicate_archives: usize,
+    languages: Vec<LanguageTotal>,
+    /// Totals of the per-repository `--language-breakdown`s; unlike
+    /// `languages`, a polyglot repository counts toward each of its
+    /// languages.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    language_bytes: Vec<LanguageTotal>,
+    largest: Vec<RepoSize>,
+    /// `--flag-depth`, when given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flag_depth: Option<u32>,
+    /// Downloaded repositories whose tree is deeper than `flag_depth`,
+    /// deepest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    deep: Vec<RepoDepth>,
+    repos: Vec<RepoRecord>,
+}
+
+impl Summary {
+    /// Flags the repositories deeper than `--flag-depth`.
+    fn with_flag_depth(mut self, flag_depth: Option<u32>) -> Self {
+        let Some(limit) = flag_depth else {
+            return self;
+        };
+        self.flag_depth = Some(limit);
+        self.deep = self
+            .repos
+            .iter()
+            .filter(|r| r.status == RepoStatus::Downloaded)
+            .filter_map(|r| {
+                r.tree_depth
+                    .filter(|depth| *depth > limit)
+                    .map(|depth| RepoDepth {
+                        name: r.name.clone(),
+                        depth,
+                    })
+            })
+            .collect();
+        self.deep
+            .sort_by(|a, b| b.depth.cmp(&a.depth).then(a.name.cmp(&b.name)));
+        self
+    }
+
+    fn new(manifest: &Manifest) -> Self {
+        let downloaded: Vec<&RepoRecord> = manifest
+            .repos
+            .iter()
+            .filter(|r| r.status == RepoStatus::Downloaded)
+            .collect();
+
+        let mut languages: Vec<LanguageTotal> = Vec::new();
+        for record in &downloaded {
+            let language = record.effective_language().unwrap_or("Unknown");
+            match languages.iter_mut().find(|l| l.language == language) {
+                Some(total) => {
+                    total.repos += 1;
+                    total.bytes += record.bytes;
+                }
+                None => languages.push(LanguageTotal {
+                    language: language.to_string(),
+                    repos: 1,
+                    bytes: record.bytes,
+                }),
+            }
+        }
+        languages.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.language.cmp(&b.language)));
+
+        let mut language_bytes: Vec<LanguageTotal> = Vec::new();
+        for (language, bytes) in downloaded.iter().flat_map(|r| &r.language_bytes) {
+            match language_bytes.iter_mut().find(|l| l.language == *language) {
+                Some(total) => {
+                    total.repos += 1;
+                    total.bytes += bytes;
+                }
+                None => language_bytes.push(LanguageTotal {
+                    language: language.clone(),
+                    repos: 1,
+                    bytes: *bytes,
+                }),
+            }
+        }
+        language_bytes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.language.cmp(&b.language)));
+
+        let mut largest: Vec<RepoSize> = downloaded
+            .iter()
+            .map(|r| RepoSize {
+                name: r.name.clone(),
+                bytes: r.bytes,
+            })
+            .collect();
+        largest.sort_by_key(|r| std::cmp::Reverse(r.bytes));
+        largest.truncate(LARGEST_REPOS_SHOWN);
+
+        Self {
+            owner: manifest.owner.clone(),
+            downloaded: downloaded.len(),
+            failed: manifest.repos.len() - downloaded.len(),
+            stalled: manifest
+                .repos
+                .iter()
+                .filter(|r| r.status == RepoStatus::Stalled)
+                .count(),
+            mirror_failed: downloaded
+                .iter()
+                .filter(|r| r.mirror.as_ref().is_some_and(|m| m.error.is_some()))
+                .count(),
+            total_size: downloaded.iter().map(|r| r.bytes).sum(),
+            elapsed_secs: manifest.elapsed_secs,
+            files_skipped: manifest.files_skipped,
+            dedupe_bytes_saved: manifest.dedupe_bytes_saved,
+            dedupe_files_linked: manifest.dedupe_files_linked,
+            archive_bytes_streamed: manifest.archive_bytes_streamed,
+            duplicate_archives: downloaded
+                .iter()
+                .filter(|r| r.duplicate_of.is_some())
+                .count(),
+            languages,
+            language_bytes,
+            largest,
+            flag_depth: None,
+            deep: Vec::new(),
+            repos: manifest.repos.clone(),
+        }
+    }
+}
+
+impl RepoInfo {
+    fn owner(&self) -> &str {
+        self.full_name.split('/').next().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+struct RefInfo {
+    name: String,
+}
+
+/// `/repos/{owner}/{repo}/commits/{ref}` response, trimmed to the signature
+/// and commit date.
+#[derive(Deserialize)]
+struct CommitInfo {
+    sha: String,
+    commit: CommitDetail,
+}
+
+#[derive(Deserialize)]
+struct CommitDetail {
+    verification: Verification,
+    #[serde(default)]
+    committer: Option<CommitPerson>,
+}
+
+#[derive(Deserialize)]
+struct CommitPerson {
+    /// ISO 8601 in UTC, so it orders as text.
+    date: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Verification {
+    verified: bool,
+    reason: String,
+}
+
+/// Signature status of a downloaded ref's head commit, as GitHub verified
+/// it. `reason` is GitHub's code, e.g. `valid`, `unsigned` or `unknown_key`.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+struct CommitSignature {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    sha: String,
+    verified: bool,
+    reason: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct RepoInfo {
+    #[serde(default)]
+    id: u64,
+    name: String,
+    full_name: String,
+    html_url: String,
+    clone_url: String,
+    language: Option<String>,
+    #[serde(rename = "stargazers_count")]
+    stars: u32,
+    size: u32,
+    #[serde(rename = "fork")]
+    is_fork: bool,
+    default_branch: String,
+    pushed_at: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    homepage: Option<String>,
+    #[serde(default)]
+    topics: Vec<String>,
+    #[serde(default)]
+    visibility: Option<String>,
+    /// Immediate upstream of a fork; only present on single-repository
+    /// responses, not in listings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<Box<RepoInfo>>,
+    /// Root of the fork network, which `parent` may itself be a fork of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Box<RepoInfo>>,
+    /// Head commit of the default branch, resolved by
+    /// `--prefetch-metadata-only`; never part of an API response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    head_sha: Option<String>,
+}
+
+/// Branches fetched per repository instead of just the default one.
+enum BranchSelection {
+    /// `--branches`: these names; missing ones are skipped.
+    List(Vec<String>),
+    /// `--all-branches`: whatever the branches API lists.
+    All,
+}
+
+/// Branch names become nested directories (`feature/x` goes to
+/// `{repo}/feature/x/`), so only plain relative components are allowed.
+fn is_safe_branch_dir(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+/// Which upstream `--follow-forks-upstream` replaces a fork with.
+#[derive(Clone, Copy)]
+enum Upstream {
+    /// The network root (`source`), however many forks deep.
+    Root,
+    /// The immediate parent, from `--follow-to-parent`.
+    Parent,
+}
+
+#[derive(Deserialize)]
+struct RateLimitResponse {
+    rate: RateLimit,
+}
+
+#[derive(Deserialize)]
+struct RateLimit {
+    limit: u32,
+    remaining: u32,
+    reset: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiMessage {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct User {
+    login: String,
+}
+
+#[derive(Default)]
+struct Config {
+    token: Option<String>,
+    ca_bundle: Option<PathBuf>,
+    insecure: bool,
+    tls_backend: Option<TlsBackend>,
+    dedupe: bool,
+    dedupe_archives: bool,
+    /// `--cache-dir` and its `--cache-max-size` in bytes.
+    archive_cache: Option<(PathBuf, u64)>,
+    extensions: Vec<String>,
+    empty_dirs: EmptyDirs,
+    strip_components: usize,
+    ref_in_dirname: bool,
+    overlay_safe: bool,
+    resolve_symlinks: bool,
+    language_detect: bool,
+    per_repo_log: bool,
+    latest_tag: bool,
+    branches: Option<BranchSelection>,
+    stall_timeout: Option<Duration>,
+    /// `--archive-generation-timeout`; `None` fails on a 202 at once.
+    archive_generation_timeout: Option<Duration>,
+    max_idle_retries: u32,
+    max_retries_large: Option<u32>,
+    /// `--max-wait`, when `--wait-for-rate-limit` is given.
+    wait_for_rate_limit: Option<Duration>,
+    speculative_retry: bool,
+    /// `--stream-tarball`.
+    stream_tarball: bool,
+    /// `--compact-small-repos`, in KB.
+    compact_small_repos: Option<u32>,
+    verify_signatures: bool,
+    language_breakdown: bool,
+    /// `--trial-run-extract`.
+    trial_run: bool,
+    mode: DownloadMode,
+    branch_strategy: BranchStrategy,
+    /// `--from-lock` pins.
+    pinned: Option<Vec<LockedRepo>>,
+    /// `--depth` for clones.
+    depth: Option<u32>,
+    /// `--mirror-to` remote template.
+    mirror_to: Option<String>,
+    concurrency_per_host: Option<usize>,
+    /// `--max-concurrent-extractions-memory`, in MB.
+    extraction_budget: Option<u32>,
+    /// `--max-inflight-bytes`, in bytes.
+    inflight_budget: Option<u64>,
+    events: Option<Arc<EventBus>>,
+    /// Sends every request in place of the reqwest clients, e.g. a
+    /// scripted one in tests.
+    transport: Option<Arc<dyn Transport>>,
+}
+
+struct ExtractOptions {
+    dedupe: Option<DedupeIndex>,
+    /// Lowercased extensions without the leading dot; empty extracts everything.
+    extensions: Vec<String>,
+    empty_dirs: EmptyDirs,
+    /// `--strip-components`.
+    strip_components: usize,
+    /// `--overlay-safe`: only regular files and directories reach the disk.
+    overlay_safe: bool,
+    /// `--resolve-symlinks`: under `overlay_safe`, symlinks to files in the
+    /// repository become copies of them.
+    resolve_symlinks: bool,
+    /// Fail on unsafe paths and on files that did not come out whole,
+    /// instead of skipping past them (`--trial-run-extract`).
+    verify: bool,
+}
+
+/// What happens to directories that hold no extracted files.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum EmptyDirs {
+    /// Create explicit directory entries and parents of extracted files.
+    #[default]
+    Default,
+    /// Also create the directories of files skipped by filtering.
+    Keep,
+    /// Remove every directory left empty once extraction finishes.
+    Prune,
+}
+
+impl ExtractOptions {
+    fn wants(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => self.extensions.contains(&ext.to_lowercase()),
+            None => false,
+        }
+    }
+
+    /// `path` without its first `strip_components` components, like `tar
+    /// --strip-components`. Entries with no components left, such as the
+    /// wrapper directories themselves, give `None` and are skipped.
+    fn strip(&self, path: &Path) -> Option<PathBuf> {
+        let stripped: PathBuf = path.components().skip(self.strip_components).collect();
+        (!stripped.as_os_str().is_empty()).then_some(stripped)
+    }
+}
+
+struct Scraper {
+    client: Client,
+    /// Executes the requests `client` builds.
+    transport: Arc<dyn Transport>,
+    /// Executes requests that must go out without the token.
+    anonymous_transport: Option<Arc<dyn Transport>>,
+    token: Option<String>,
+    extract: ExtractOptions,
+    files_skipped: AtomicU64,
+    language_detect: bool,
+    per_repo_log: bool,
+    latest_tag: bool,
+    branches: Option<BranchSelection>,
+    stall_timeout: Option<Duration>,
+    /// `--archive-generation-timeout`; `None` fails on a 202 at once.
+    archive_generation_timeout: Option<Duration>,
+    max_idle_retries: u32,
+    max_retries_large: Option<u32>,
+    speculative_retry: bool,
+    stream_tarball: bool,
+    /// Compressed bytes of `--stream-tarball` archives, none of which were
+    /// written to disk.
+    archive_bytes_streamed: AtomicU64,
+    tail: TailMonitor,
+    bundles: Option<SmallRepoBundles>,
+    archives: Option<ArchiveIndex>,
+    archive_cache: Option<ArchiveCache>,
+    verify_signatures: bool,
+    language_breakdown: bool,
+    trial_run: bool,
+    mode: DownloadMode,
+    branch_strategy: BranchStrategy,
+    depth: Option<u32>,
+    mirror_to: Option<String>,
+    host_limiter: Option<HostLimiter>,
+    extraction_budget: Option<ExtractionBudget>,
+    inflight_budget: Option<InflightBudget>,
+    events: Option<Arc<EventBus>>,
+    /// Head commits from `--metadata-cache`, by `owner/name@branch`, which
+    /// spare `--cache-dir` its lookup.
+    head_shas: std::sync::Mutex<HashMap<String, String>>,
+    /// Commit of the last archive extracted for each `owner/name`, taken
+    /// by `download_repo` for its record.
+    archive_commits: std::sync::Mutex<HashMap<String, String>>,
+    /// `--from-lock` pins; repositories are downloaded at these commits.
+    pinned: Option<Vec<LockedRepo>>,
+    ref_dirs: Option<RefDirs>,
+    cancel: CancelToken,
+    /// `--max-wait`, when `--wait-for-rate-limit` is given.
+    wait_for_rate_limit: Option<Duration>,
+    /// Latest `X-RateLimit-Reset` announced, so concurrent requests
+    /// hitting the same exhausted quota print one message.
+    rate_limit_announced: AtomicU64,
+}
+
+/// Completion times of the current pass, used by `--speculative-retry` to
+/// spot downloads lagging far behind the rest once nothing is left to
+/// schedule.
+#[derive(Default)]
+struct TailMonitor {
+    all_scheduled: std::sync::atomic::AtomicBool,
+    durations: std::sync::Mutex<Vec<Duration>>,
+}
+
+impl TailMonitor {
+    fn start_pass(&self) {
+        self.all_scheduled.store(false, Ordering::Relaxed);
+        self.durations.lock().unwrap().clear();
+    }
+
+    fn all_scheduled(&self) {
+        self.all_scheduled.store(true, Ordering::Relaxed);
+    }
+
+    fn record(&self, duration: Duration) {
+        self.durations.lock().unwrap().push(duration);
+    }
+
+    /// A download is a straggler once every repository has been scheduled
+    /// and it has run several times longer than the median completed one.
+    fn is_straggler(&self, elapsed: Duration) -> bool {
+        if !self.all_scheduled.load(Ordering::Relaxed) || elapsed < SPECULATE_AFTER_MIN {
+            return false;
+        }
+
+        let mut durations = self.durations.lock().unwrap().clone();
+        if durations.len() < SPECULATE_MIN_SAMPLES {
+            return false;
+        }
+        durations.sort();
+        elapsed > durations[durations.len() / 2] * SPECULATE_FACTOR
+    }
+}
+
+/// Caps concurrent archive downloads per host under `--concurrency-per-host`,
+/// independently of the global `--parallel` limit.
+struct HostLimiter {
+    per_host: usize,
+    hosts: std::sync::Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    fn new(per_host: usize) -> Self {
+        Self {
+            per_host,
+            hosts: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(&self, url: &str) -> tokio::sync::OwnedSemaphorePermit {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .unwrap_or_default();
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone();
+        semaphore.acquire_owned().await.unwrap()
+    }
+}
+
+/// Megabyte budget shared by concurrent extractions under
+/// `--max-concurrent-extractions-memory`. Each extraction reserves its
+/// archive's declared uncompressed size, capped at the whole budget so an
+/// oversized archive still runs, alone.
+struct ExtractionBudget {
+    semaphore: Arc<Semaphore>,
+    total_mb: u32,
+}
+
+impl ExtractionBudget {
+    fn new(total_mb: u32) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(total_mb as usize)),
+            total_mb,
+        }
+    }
+
+    async fn reserve(&self, zip_path: &Path, log: &RepoLog) -> tokio::sync::OwnedSemaphorePermit {
+        let bytes = declared_uncompressed_size(zip_path).unwrap_or(0);
+        let mb = bytes
+            .div_ceil(1024 * 1024)
+            .clamp(1, u64::from(self.total_mb)) as u32;
+        if self.semaphore.available_permits() < mb as usize {
+            log.event(format!("waiting for {} MB of extraction budget", mb));
+        }
+        self.semaphore.clone().acquire_many_owned(mb).await.unwrap()
+    }
+}
+
+/// What a download is assumed to hold in memory under `--max-inflight-bytes`:
+/// archives stream to disk, so only the chunks in transit count.
+const INFLIGHT_DOWNLOAD_RESERVE: u64 = 4 * 1024 * 1024;
+
+/// One byte budget shared by downloads and extractions under
+/// `--max-inflight-bytes`, counted in KiB. A download is admitted with
+/// `INFLIGHT_DOWNLOAD_RESERVE`; before extracting, it trades that for its
+/// archive's declared uncompressed size, so a task never waits while holding
+/// budget another one needs. Reservations are capped at the whole budget, so
+/// an oversized archive still runs, alone.
+struct InflightBudget {
+    semaphore: Arc<Semaphore>,
+    total_kb: u32,
+}
+
+impl InflightBudget {
+    fn new(total_bytes: u64) -> Self {
+        let total_kb = total_bytes.div_ceil(1024).min(u64::from(u32::MAX)) as u32;
+        Self {
+            semaphore: Arc::new(Semaphore::new(total_kb as usize)),
+            total_kb,
+        }
+    }
+
+    async fn reserve(
+        &self,
+        bytes: u64,
+        what: &str,
+        log: &RepoLog,
+    ) -> tokio::sync::OwnedSemaphorePermit {
+        let kb = bytes.div_ceil(1024).clamp(1, u64::from(self.total_kb)) as u32;
+        if self.semaphore.available_permits() < kb as usize {
+            log.event(format!(
+                "waiting for {} KB of in-flight budget to {}",
+                kb, what
+            ));
+        }
+        self.semaphore.clone().acquire_many_owned(kb).await.unwrap()
+    }
+
+    async fn admit_download(&self, log: &RepoLog) -> tokio::sync::OwnedSemaphorePermit {
+        self.reserve(INFLIGHT_DOWNLOAD_RESERVE, "download", log)
+            .await
+    }
+
+    async fn admit_extraction(
+        &self,
+        download: tokio::sync::OwnedSemaphorePermit,
+        zip_path: &Path,
+        log: &RepoLog,
+    ) -> tokio::sync::OwnedSemaphorePermit {
+        drop(download);
+        let bytes = declared_uncompressed_size(zip_path).unwrap_or(0);
+        self.reserve(bytes, "extract", log).await
+    }
+}
+
+/// Parses `--max-inflight-bytes` and `--cache-max-size`: a number, which
+/// may be fractional, then an optional unit, e.g. `4096`, `512M`, `1.5G` or
+/// `2 GiB`. Units are binary however they are spelled, so `1K`, `1KB` and
+/// `1KiB` are all 1024 bytes.
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid size '{}' (expected a number and an optional B, K, M, G or T unit, \
+             with or without B/iB, e.g. 4096, 512M, 512MiB, 1.5G or \"2 GB\")",
+            value
+        )
+    };
+    let trimmed = value.trim();
+    let unit_start = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(unit_start);
+    let exponent = match unit.trim_start().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        _ => return Err(invalid()),
+    };
+    let multiplier = 1024_u64.pow(exponent);
+    let size = if number.contains('.') {
+        let size = number.parse::<f64>().map_err(|_| invalid())? * multiplier as f64;
+        // `as` saturates, so anything at or past the limit is refused.
+        (size < u64::MAX as f64).then_some(size.round() as u64)
+    } else {
+        let count = number.parse::<u64>().map_err(|_| invalid())?;
+        count.checked_mul(multiplier)
+    };
+    let size = size.ok_or_else(|| format!("size '{}' is too large", value))?;
+    if size == 0 {
+        return Err("size must be greater than zero".to_string());
+    }
+    Ok(size)
+}
+
+/// Formats a byte count for display in binary units with one decimal,
+/// e.g. `512 B`, `1.5 KiB`, `20.0 MiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // Promote before rounding would print `1024.0 KiB`.
+    while value >= 1023.95 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats a transfer rate with the units of [`format_size`].
+fn format_rate(bytes: u64, secs: f64) -> String {
+    if secs <= 0.0 {
+        return format!("{}/s", format_size(0));
+    }
+    format!("{}/s", format_size((bytes as f64 / secs) as u64))
+}
+
+/// `--cache-max-size` when only `--cache-dir` is given.
+const ARCHIVE_CACHE_DEFAULT_MAX: u64 = 10 * 1024 * 1024 * 1024;
+
+/// `--cache-dir`: archives kept across runs and output directories, one
+/// file per repository and commit. Entries are immutable once renamed into
+/// place, so concurrent runs can share a cache; modification times double
+/// as last-use times for eviction.
+struct ArchiveCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Serializes this process's evictions.
+    evicting: std::sync::Mutex<()>,
+}
+
+impl ArchiveCache {
+    fn new(dir: PathBuf, max_bytes: u64) -> Result<Self, String> {
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Cannot create cache directory {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir,
+            max_bytes,
+            evicting: std::sync::Mutex::new(()),
+        })
+    }
+
+    fn entry(&self, repo: &str, sha: &str) -> PathBuf {
+        let key = format!("{}@{}", repo.to_lowercase(), sha);
+        let digest = Sha256::digest(key.as_bytes());
+        let name: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(format!("{}.zip", name))
+    }
+
+    /// Puts a cached archive where a download of `repo_path` would have
+    /// left it, replacing any partial download, and marks the entry used.
+    fn restore(&self, entry: &Path, repo_path: &Path, log: &RepoLog) -> Option<PathBuf> {
+        if !entry.is_file() {
+            return None;
+        }
+        let (part_path, state_path) = partial_paths(repo_path);
+        fs::remove_file(&state_path).ok();
+        fs::remove_file(&part_path).ok();
+        if let Some(parent) = part_path.parent() {
+            fs::create_dir_all(parent).ok()?;
+        }
+        if let Err(e) = link_or_copy(entry, &part_path) {
+            log.event(format!("could not use cached archive ({}), downloading", e));
+            return None;
+        }
+        if let Ok(file) = fs::File::options().write(true).open(entry) {
+            file.set_modified(std::time::SystemTime::now()).ok();
+        }
+        log.event(format!("restored from cache {}", entry.display()));
+        Some(part_path)
+    }
+
+    /// Adds a downloaded archive, then evicts past the size limit. Failures
+    /// only cost the cache entry.
+    fn store(&self, zip_file: &Path, entry: &Path, log: &RepoLog) {
+        let tmp = entry.with_extension(format!("zip.{}.tmp", std::process::id()));
+        let stored = link_or_copy(zip_file, &tmp).and_then(|_| fs::rename(&tmp, entry));
+        if let Err(e) = stored {
+            fs::remove_file(&tmp).ok();
+            log.event(format!("could not cache archive: {}", e));
+            return;
+        }
+        log.event(format!("cached as {}", entry.display()));
+        self.evict(entry);
+    }
+
+    /// Removes the least recently used entries until the cache fits in
+    /// `max_bytes`. `keep`, the entry just stored, is never evicted, even
+    /// if it alone is over the limit.
+    fn evict(&self, keep: &Path) {
+        let _evicting = self.evicting.lock().unwrap();
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut entries: Vec<(std::time::SystemTime, u64, PathBuf)> = dir
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "zip" {
+                    return None;
+                }
+                let metadata = fs::metadata(&path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), path))
+            })
+            .collect();
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if path != keep && fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+}
+
+/// Hardlinks `from` to `to`, copying when they are on different
+/// filesystems.
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to).map(|_| ())
+}
+
+/// Sum of the uncompressed sizes the archive's central directory declares.
+fn declared_uncompressed_size(zip_path: &Path) -> Option<u64> {
+    let mut archive = ZipArchive::new(fs::File::open(zip_path).ok()?).ok()?;
+    let mut total = 0;
+    for i in 0..archive.len() {
+        total += archive.by_index_raw(i).ok()?.size();
+    }
+    Some(total)
+}
+
+/// Why a repository could not be downloaded. Stalls are kept apart from
+/// other failures: the branch exists and a later run usually succeeds.
+#[derive(Debug)]
+enum DownloadError {
+    Stalled(String),
+    /// GitHub answered 404: there is no archive for the ref tried.
+    NotFound(String),
+    Failed(String),
+}
+
+impl From<String> for DownloadError {
+    fn from(message: String) -> Self {
+        DownloadError::Failed(message)
+    }
+}
+
+impl DownloadError {
+    /// Whether GitHub had no archive to give: no branch we tried exists.
+    /// `--mode auto` clones such repositories instead.
+    fn is_missing_archive(&self) -> bool {
+        matches!(self, DownloadError::NotFound(_))
+    }
+
+    /// The same error with `context` in front of its message.
+    fn context(self, context: &str) -> Self {
+        match self {
+            DownloadError::Stalled(message) => {
+                DownloadError::Stalled(format!("{}: {}", context, message))
+            }
+            DownloadError::NotFound(message) => {
+                DownloadError::NotFound(format!("{}: {}", context, message))
+            }
+            DownloadError::Failed(message) => {
+                DownloadError::Failed(format!("{}: {}", context, message))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DownloadError::Stalled(message)
+            | DownloadError::NotFound(message)
+            | DownloadError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TlsBackend {
+    Native,
+    Rustls,
+}
+
+/// Selects the requested TLS implementation, or leaves reqwest's default
+/// when none was asked for. Backends are cargo features, so a choice that
+/// was not compiled in is reported rather than silently ignored.
+fn with_tls_backend(
+    builder: reqwest::ClientBuilder,
+    backend: Option<TlsBackend>,
+) -> Result<reqwest::ClientBuilder, String> {
+    match backend {
+        None => Ok(builder),
+        #[cfg(feature = "native-tls")]
+        Some(TlsBackend::Native) => Ok(builder.use_native_tls()),
+        #[cfg(feature = "rustls")]
+        Some(TlsBackend::Rustls) => Ok(builder.use_rustls_tls()),
+        #[allow(unreachable_patterns)]
+        Some(backend) => {
+            let feature = match backend {
+                TlsBackend::Native => "native-tls",
+                TlsBackend::Rustls => "rustls",
+            };
+            Err(format!(
+                "TLS backend '{}' is not compiled into this build; rebuild with --features {}",
+                feature, feature
+            ))
+        }
+    }
+}
+
+/// What a successful `download_repo` produced.
+struct Download {
+    bytes: u64,
+    detected_language: Option<String>,
+    /// Bytes per language from the API under `--language-breakdown`.
+    language_bytes: BTreeMap<String, u64>,
+    /// Tag downloaded under `--latest-tag`; `None` means the default branch.
+    tag: Option<String>,
+    /// Branches present under `--branches`/`--all-branches`.
+    branches: Vec<String>,
+    /// Grouped archive holding the repository under `--compact-small-repos`.
+    bundle: Option<String>,
+    /// Head commit signature checked by `--verify-signatures`.
+    signature: Option<CommitSignature>,
+    /// Outcome of the `--mirror-to` push.
+    mirror: Option<MirrorPush>,
+    /// Cloned rather than extracted under `--mode auto`.
+    cloned: bool,
+    /// Repository whose extraction was linked under `--dedupe-archives`.
+    duplicate_of: Option<String>,
+    /// Directory name under `--ref-in-dirname`.
+    dir: Option<String>,
+    /// Depth of the extracted tree; `None` for bundled and cloned ones.
+    tree_depth: Option<u32>,
+    /// Commit the archive was made from; `None` when nothing was extracted.
+    commit: Option<String>,
+    /// Repository whose directory this one would have shared, under
+    /// `--rename-on-conflict`.
+    conflicts_with: Option<String>,
+}
+
+impl Scraper {
+    fn new(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let token = config.token;
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("Accept", "application/vnd.github.v3+json".parse().unwrap());
+        headers.insert("User-Agent", "magnet/2.0".parse().unwrap());
+        let anonymous_headers = headers.clone();
+
+        if let Some(ref token) = token {
+            headers.insert(
+                "Authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+
+        let mut certs = Vec::new();
+        if let Some(path) = &config.ca_bundle {
+            let pem = fs::read(path)
+                .map_err(|e| format!("Cannot read CA bundle {}: {}", path.display(), e))?;
+            certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("Invalid CA bundle {}: {}", path.display(), e))?;
+            if certs.is_empty() {
+                return Err(format!("No certificates found in {}", path.display()).into());
+            }
+        }
+
+        if config.insecure {
+            eprintln!("**************************************************************");
+            eprintln!("WARNING: TLS certificate verification is DISABLED");
+            eprintln!("Any network intermediary can read and modify this traffic,");
+            eprintln!("including your GitHub token. Use --ca-bundle instead.");
+            eprintln!("**************************************************************");
+        }
+
+        let build_client = |headers: reqwest::header::HeaderMap| {
+            let mut builder = Client::builder()
+                .timeout(Duration::from_secs(300))
+                .default_headers(headers);
+            for cert in &certs {
+                builder = builder.add_root_certificate(cert.clone());
+            }
+            if config.insecure {
+                builder = builder.danger_accept_invalid_certs(true);
+            }
+            with_tls_backend(builder, config.tls_backend)?
+                .build()
+                .map_err(|e| {
+                    format!(
+                        "TLS backend unavailable ({}); rebuild with the rustls feature \
+                         (cargo build --no-default-features --features rustls)",
+                        e
+                    )
+                })
+        };
+
+        let archive_cache = match config.archive_cache {
+            Some((dir, max_bytes)) => Some(ArchiveCache::new(dir, max_bytes)?),
+            None => None,
+        };
+
+        let client = build_client(headers)?;
+        // Used to tell "missing" from "hidden from this token" on 404s.
+        let anonymous_transport: Option<Arc<dyn Transport>> = match (&token, &config.transport) {
+            (None, _) => None,
+            (Some(_), Some(transport)) => Some(transport.clone()),
+            (Some(_), None) => Some(Arc::new(build_client(anonymous_headers)?)),
+        };
+
+        Ok(Self {
+            transport: config.transport.unwrap_or_else(|| Arc::new(client.clone())),
+            client,
+            anonymous_transport,
+            token,
+            extract: ExtractOptions {
+                dedupe: config.dedupe.then(DedupeIndex::new),
+                extensions: config.extensions,
+                empty_dirs: config.empty_dirs,
+                strip_components: config.strip_components,
+                overlay_safe: config.overlay_safe,
+                resolve_symlinks: config.resolve_symlinks,
+                verify: config.trial_run,
+            },
+            files_skipped: AtomicU64::new(0),
+            language_detect: config.language_detect,
+            per_repo_log: config.per_repo_log,
+            latest_tag: config.latest_tag,
+            branches: config.branches,
+            stall_timeout: config.stall_timeout,
+            archive_generation_timeout: config.archive_generation_timeout,
+            max_idle_retries: config.max_idle_retries,
+            max_retries_large: config.max_retries_large,
+            speculative_retry: config.speculative_retry,
+            stream_tarball: config.stream_tarball,
+            archive_bytes_streamed: AtomicU64::new(0),
+            tail: TailMonitor::default(),
+            bundles: config.compact_small_repos.map(SmallRepoBundles::new),
+            archives: config.dedupe_archives.then(ArchiveIndex::new),
+            archive_cache,
+            verify_signatures: config.verify_signatures,
+            language_breakdown: config.language_breakdown,
+            trial_run: config.trial_run,
+            mode: config.mode,
+            branch_strategy: config.branch_strategy,
+            depth: config.depth,
+            mirror_to: config.mirror_to,
+            host_limiter: config.concurrency_per_host.map(HostLimiter::new),
+            extraction_budget: config.extraction_budget.map(ExtractionBudget::new),
+            inflight_budget: config.inflight_budget.map(InflightBudget::new),
+            events: config.events,
+            head_shas: std::sync::Mutex::default(),
+            archive_commits: std::sync::Mutex::default(),
+            pinned: config.pinned,
+            ref_dirs: config.ref_in_dirname.then(RefDirs::default),
+            cancel: CancelToken::default(),
+            wait_for_rate_limit: config.wait_for_rate_limit,
+            rate_limit_announced: AtomicU64::new(0),
+        })
+    }
+
+    async fn fetch_all_repos(&self, username: &str) -> Result<Vec<RepoInfo>, String> {
+        let mut repos = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let data = self.fetch_repos_page(username, page).await?;
+
+            if data.is_empty() {
+                break;
+            }
+
+            repos.extend(data);
+            page += 1;
+        }
+
+        if self.token.is_none() {
+            self.check_rate_limit().await.ok();
+        }
+
+        Ok(repos)
+    }
+
+    /// Patterns for `--repo-blocklist-url`. A copy younger than
+    /// `BLOCKLIST_CACHE_TTL` is used without asking the URL; an unreachable
+    /// URL falls back to the last copy, however old, or to no blocklist,
+    /// with a warning either way. The token is never sent to the URL.
+    async fn load_blocklist(&self, url: &str) -> Vec<BlockPattern> {
+        let cache = blocklist_cache_path(url);
+        let cached_age = fs::metadata(&cache)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if cached_age.is_some_and(|age| age < BLOCKLIST_CACHE_TTL)
+            && let Ok(text) = fs::read_to_string(&cache)
+        {
+            return parse_blocklist(&text);
+        }
+
+        let fetched = async {
+            let response = self
+                .send_anonymous(self.client.get(url))
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()));
+            }
+            response.text().await.map_err(|e| e.to_string())
+        };
+
+        match fetched.await {
+            Ok(text) => {
+                fs::write(&cache, &text).ok();
+                parse_blocklist(&text)
+            }
+            Err(e) => match fs::read_to_string(&cache) {
+                Ok(text) => {
+                    eprintln!(
+                        "WARNING: Could not fetch blocklist {} ({}); using the copy from an earlier run",
+                        url, e
+                    );
+                    parse_blocklist(&text)
+                }
+                Err(_) => {
+                    eprintln!(
+                        "WARNING: Could not fetch blocklist {} ({}); continuing without it",
+                        url, e
+                    );
+                    Vec::new()
+                }
+            },
+        }
+    }
+
+    /// One page of `username`'s repositories; empty past the last one.
+    async fn fetch_repos_page(&self, username: &str, page: u32) -> Result<Vec<RepoInfo>, String> {
+        let url = format!(
+            "{}/users/{}/repos?per_page=100&page={}",
+            GITHUB_API_BASE, username, page
+        );
+
+        let response = self.retry_request(None, || self.client.get(&url)).await?;
+
+        if !response.status().is_success() {
+            return Err(self
+                .describe_api_error(response, &url, &format!("user {}", username))
+                .await);
+        }
+
+        response.json().await.map_err(|e| e.to_string())
+    }
+
+    /// Replaces every fork with its upstream and drops repeats, so a dozen
+    /// forks in one network download the upstream once. A fork whose
+    /// upstream cannot be looked up is kept as it is.
+    async fn follow_forks_upstream(
+        &self,
+        repos: Vec<RepoInfo>,
+        upstream: Upstream,
+    ) -> Vec<RepoInfo> {
+        let mut followed: Vec<RepoInfo> = Vec::new();
+        for repo in repos {
+            let repo = if repo.is_fork {
+                let (owner, name) = (repo.owner().to_string(), repo.name.clone());
+                match self.fetch_repo(&owner, &name).await {
+                    Ok(details) => {
+                        let target = match upstream {
+                            Upstream::Root => details.source.or(details.parent),
+                            Upstream::Parent => details.parent,
+                        };
+                        match target {
+                            Some(target) => *target,
+                            None => repo,
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("WARNING: Keeping fork {}: {}", repo.full_name, e);
+                        repo
+                    }
+                }
+            } else {
+                repo
+            };
+
+            if !followed.iter().any(|r| r.full_name == repo.full_name) {
+                followed.push(repo);
+            }
+        }
+        followed
+    }
+
+    /// Fills in each repository's `head_sha` from its default branch, up to
+    /// `parallel` lookups at a time. A repository whose lookup fails keeps
+    /// `None` and is looked up again when it is downloaded, if at all.
+    async fn resolve_head_commits(
+        self: &Arc<Self>,
+        repos: Vec<RepoInfo>,
+        parallel: usize,
+    ) -> Vec<RepoInfo> {
+        let semaphore = Arc::new(Semaphore::new(parallel.max(1)));
+        let mut lookups = Vec::new();
+        for mut repo in repos {
+            let scraper = self.clone();
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            lookups.push(tokio::spawn(async move {
+                let _permit = permit;
+                let url = format!(
+                    "{}/repos/{}/commits/{}",
+                    GITHUB_API_BASE, repo.full_name, repo.default_branch
+                );
+                repo.head_sha = match scraper
+                    .retry_request(None, || scraper.client.get(&url))
+                    .await
+                {
+                    Ok(response) if response.status().is_success() => response
+                        .json::<CommitInfo>()
+                        .await
+                        .ok()
+                        .map(|info| info.sha),
+                    _ => None,
+                };
+                repo
+            }));
+        }
+
+        let mut resolved = Vec::new();
+        for lookup in lookups {
+            if let Ok(repo) = lookup.await {
+                resolved.push(repo);
+            }
+        }
+        resolved
+    }
+
+    /// Looks up each listed repository, warning about and skipping any that
+    /// cannot be fetched so one bad line does not sink the whole list.
+    async fn fetch_listed_repos(&self, entries: &[(String, String)]) -> Vec<RepoInfo> {
+        let mut repos = Vec::new();
+        for (owner, name) in entries {
+            match self.fetch_repo(owner, name).await {
+                Ok(repo) => repos.push(repo),
+                Err(e) => eprintln!("WARNING: Skipping {}/{}: {}", owner, name, e),
+            }
+        }
+        repos
+    }
+
+    async fn fetch_repo(&self, owner: &str, name: &str) -> Result<RepoInfo, String> {
+        let url = format!("{}/repos/{}/{}", GITHUB_API_BASE, owner, name);
+        let response = self.retry_request(None, || self.client.get(&url)).await?;
+
+        if !response.status().is_success() {
+            return Err(self
+                .describe_api_error(response, &url, &format!("repository {}/{}", owner, name))
+                .await);
+        }
+
+        response.json().await.map_err(|e| e.to_string())
+    }
+
+    /// Turns a failed API response into an actionable message. Fine-grained
+    /// tokens answer 403, or even 404, for accounts and repositories outside
+    /// their selected scope, which otherwise reads as "does not exist".
+    async fn describe_api_error(
+        &self,
+        response: reqwest::Response,
+        url: &str,
+        resource: &str,
+    ) -> String {
+        let status = response.status();
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let accepted_permissions = header("x-accepted-github-permissions");
+        let sso = header("x-github-sso");
+        let message = response
+            .json::<ApiMessage>()
+            .await
+            .map(|m| m.message)
+            .unwrap_or_default();
+
+        if self.token.is_none() {
+            return match status {
+                StatusCode::NOT_FOUND => format!("{} not found", resource),
+                _ => format!("GitHub API error: {} {}", status, message)
+                    .trim_end()
+                    .to_string(),
+            };
+        }
+
+        match status {
+            StatusCode::UNAUTHORIZED => {
+                "GitHub rejected the token (HTTP 401): it is invalid, expired or revoked"
+                    .to_string()
+            }
+            StatusCode::FORBIDDEN if sso.is_some() => format!(
+                "token must be authorized for SAML SSO to access {} ({})",
+                resource,
+                sso.unwrap_or_default()
+            ),
+            StatusCode::FORBIDDEN
+                if accepted_permissions.is_some() || message.contains("not accessible by") =>
+            {
+                let mut text = format!(
+                    "token lacks permission for {}: {}. Fine-grained tokens only reach the \
+                     accounts and repositories selected when they were created",
+                    resource, message
+                );
+                if let Some(permissions) = accepted_permissions.filter(|p| !p.is_empty()) {
+                    text.push_str(&format!(" (required permissions: {})", permissions));
+                }
+                text
+            }
+            StatusCode::NOT_FOUND => {
+                let visible_anonymously = self.anonymous_transport.is_some()
+                    && self
+                        .send_anonymous(self.client.get(url))
+                        .await
+                        .is_ok_and(|r| r.status().is_success());
+
+                if visible_anonymously {
+                    format!(
+                        "{} exists but is hidden from your token; a fine-grained token \
+                         probably does not grant access to this account or repository",
+                        resource
+                    )
+                } else {
+                    format!(
+                        "{} not found (if it is private, check that your token grants access to it)",
+                        resource
+                    )
+                }
+            }
+            _ => format!("GitHub API error: {} {}", status, message)
+                .trim_end()
+                .to_string(),
+        }
+    }
+
+    async fn check_rate_limit(&self) -> Result<(), String> {
+        let rate = self.rate_limit().await?;
+        if rate.remaining < 10 {
+            eprintln!(
+                "WARNING: GitHub API rate limit low: {} remaining",
+                rate.remaining
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn rate_limit(&self) -> Result<RateLimit, String> {
+        let url = format!("{}/rate_limit", GITHUB_API_BASE);
+        let response = self
+            .send(self.client.get(&url))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("GitHub API error: {}", response.status()));
+        }
+
+        let data: RateLimitResponse = response.json().await.map_err(|e| e.to_string())?;
+        Ok(data.rate)
+    }
+
+    async fn check_connectivity(&self) -> Result<String, String> {
+        let started = Instant::now();
+        let response = self
+            .send(self.client.get(GITHUB_API_BASE))
+            .await
+            .map_err(|e| format!("cannot reach {}: {}", GITHUB_API_BASE, e))?;
+
+        if response.status().is_server_error() {
+            return Err(format!(
+                "{} returned {}",
+                GITHUB_API_BASE,
+                response.status()
+            ));
+        }
+
+        Ok(format!(
+            "{} reachable in {} ms",
+            GITHUB_API_BASE,
+            started.elapsed().as_millis()
+        ))
+    }
+
+    async fn check_token(&self) -> Result<String, String> {
+        let url = format!("{}/user", GITHUB_API_BASE);
+        let response = self
+            .send(self.client.get(&url))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err("token rejected (HTTP 401): invalid, expired or revoked".to_string());
+        }
+
+        if !response.status().is_success() {
+            return Err(format!("GitHub API error: {}", response.status()));
+        }
+
+        let scopes = response
+            .headers()
+            .get("x-oauth-scopes")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let user: User = response.json().await.map_err(|e| e.to_string())?;
+
+        Ok(match scopes {
+            Some(scopes) if scopes.is_empty() => {
+                format!("authenticated as {} (no scopes granted)", user.login)
+            }
+            Some(scopes) => format!("authenticated as {}, scopes: {}", user.login, scopes),
+            None => format!(
+                "authenticated as {} (fine-grained token, permissions not listed)",
+                user.login
+            ),
+        })
+    }
+
+    /// Sends `request` once through the transport.
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        self.transport.execute(request.build()?).await
+    }
+
+    /// Sends `request` once without the token, through the anonymous
+    /// transport when there is a token to leave out.
+    async fn send_anonymous(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let transport = self.anonymous_transport.as_ref().unwrap_or(&self.transport);
+        transport.execute(request.build()?).await
+    }
+
+    /// Sends the request built by `build_request` through the transport,
+    /// retrying connection errors and rate-limited responses with
+    /// `backoff_delay` between attempts. Other responses, including errors,
+    /// are returned for the caller to interpret. Requests made for a
+    /// repository get that repository's `retry_budget`; the rest get
+    /// `MAX_RETRIES`.
+    async fn retry_request<F>(
+        &self,
+        log: Option<&RepoLog>,
+        mut build_request: F,
+    ) -> Result<reqwest::Response, String>
+    where
+        F: FnMut() -> reqwest::RequestBuilder,
+    {
+        let attempts = log.map_or(MAX_RETRIES, |log| log.attempts);
+        let mut last_error = None;
+        let mut last_response = None;
+
+        let mut attempt = 0;
+        while attempt < attempts {
+            let request = build_request().build().map_err(|e| e.to_string())?;
+            // Dropping the request future aborts it mid-flight.
+            let result = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => return Err(self.cancelled_error()),
+                result = self.transport.execute(request) => result,
+            };
+
+            match result {
+                Ok(response) => {
+                    if response.status().is_success() || response.status() == StatusCode::NOT_FOUND
+                    {
+                        return Ok(response);
+                    }
+
+                    // Only rate limiting is worth waiting out; a 403 for a
+                    // missing permission will not change on retry.
+                    if is_rate_limited(&response) {
+                        // Sleeping through the reset does not use up an
+                        // attempt: the request never had a chance.
+                        if let Some(wait) = self.rate_limit_wait(&response) {
+                            if let Some(log) = log {
+                                log.event(format!(
+                                    "rate limit exhausted, waiting {}s for the reset",
+                                    wait.as_secs()
+                                ));
+                            }
+                            self.backoff(wait).await?;
+                            continue;
+                        }
+
+                        let delay = backoff_delay(attempt, attempts);
+                        if let Some(log) = log {
+                            log.retry(attempt, &response.status().to_string(), delay);
+                        }
+                        last_response = Some(response);
+                        if let Some(delay) = delay {
+                            self.backoff(delay).await?;
+                        }
+                        attempt += 1;
+                        continue;
+                    }
+
+                    return Ok(response);
+                }
+                Err(e) => {
+                    let delay = backoff_delay(attempt, attempts);
+                    if let Some(log) = log {
+                        log.retry(attempt, &e.to_string(), delay);
+                    }
+                    last_error = Some(e);
+                    if let Some(delay) = delay {
+                        self.backoff(delay).await?;
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+
+        match last_response {
+            Some(response) => Ok(response),
+            None => Err(last_error.unwrap().to_string()),
+        }
+    }
+
+    /// Sleeps out a retry delay, cut short once the run is cancelled so
+    /// shutdown does not wait for a multi-second backoff to expire.
+    async fn backoff(&self, delay: Duration) -> Result<(), String> {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => Ok(()),
+            _ = self.cancel.cancelled() => Err(self.cancelled_error()),
+        }
+    }
+
+    /// Under `--wait-for-rate-limit`, how long until an exhausted quota
+    /// resets, from `X-RateLimit-Reset`; `None` when the response is not
+    /// about the hourly quota or the reset is further away than
+    /// `--max-wait`. The first request to see a given reset announces it.
+    fn rate_limit_wait(&self, response: &reqwest::Response) -> Option<Duration> {
+        let max_wait = self.wait_for_rate_limit?;
+        let headers = response.headers();
+        if headers
+            .get("x-ratelimit-remaining")
+            .is_none_or(|v| v.as_bytes() != b"0")
+        {
+            return None;
+        }
+        let reset: u64 = headers
+            .get("x-ratelimit-reset")?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+        // A second of slack for clock skew against GitHub.
+        let wait = Duration::from_secs(reset.checked_sub(now)? + 1);
+        if wait > max_wait {
+            return None;
+        }
+        if self
+            .rate_limit_announced
+            .fetch_max(reset, Ordering::Relaxed)
+            < reset
+        {
+            eprintln!(
+                "Rate limit exhausted, sleeping until {:02}:{:02} UTC ({} min)",
+                reset % 86400 / 3600,
+                reset % 3600 / 60,
+                wait.as_secs().div_ceil(60)
+            );
+        }
+        Some(wait)
+    }
+
+    fn cancelled_error(&self) -> String {
+        format!("cancelled: {}", self.cancel.reason().unwrap_or_default())
+    }
+
+    /// Attempts `retry_request` makes for each of `repo`'s requests. A 404
+    /// is definitive and never retried whatever the budget: the archive or
+    /// branch is missing, and asking again will not change that. Beyond
+    /// that, the budget follows what giving up costs: a large repository
+    /// (`LARGE_REPO_KB` or more) that fails is re-fetched in full by the next
+    /// pass, so it is worth `--max-retries-large` attempts now, while small
+    /// ones keep `MAX_RETRIES`.
+    fn retry_budget(&self, repo: &RepoInfo) -> u32 {
+        match self.max_retries_large {
+            Some(attempts) if repo.size >= LARGE_REPO_KB => attempts,
+            _ => MAX_RETRIES,
+        }
+    }
+
+    /// Downloads `repo` into `root/dir`, where `dir` is its name unless
+    /// `--rename-on-conflict` gave it another.
+    async fn download_repo(
+        &self,
+        repo: &RepoInfo,
+        root: &Path,
+        dir: &str,
+    ) -> Result<Download, DownloadError> {
+        let plain_path = root.join(dir);
+        let repo_path = match &self.ref_dirs {
+            Some(ref_dirs) => ref_dirs.resolve(&plain_path),
+            None => plain_path.clone(),
+        };
+        let log = RepoLog::new(&repo.full_name, self.retry_budget(repo));
+        log.event(format!(
+            "repository {} (default branch {})",
+            repo.full_name, repo.default_branch
+        ));
+
+        let target = match &self.bundles {
+            Some(bundles) if repo.size < bundles.threshold_kb => ArchiveTarget::Bundle(bundles),
+            _ => ArchiveTarget::Directory,
+        };
+        let mut tag = None;
+        let mut branches = Vec::new();
+        let mut cloned = is_bare_clone(&repo_path);
+        let result = if cloned {
+            log.event("already cloned, skipping download");
+            Ok(get_dir_size(&repo_path).unwrap_or(0))
+        } else if let ArchiveTarget::Bundle(bundles) = target
+            && let Some((bundle, bytes)) = bundles.locate(&repo_path)
+        {
+            log.event(format!("already in {}, skipping download", bundle));
+            Ok(bytes)
+        } else if let Some(locked) = &self.pinned {
+            self.download_pinned(repo, locked, &repo_path, target, &log)
+                .await
+        } else if self.mode == DownloadMode::Clone
+            || (self.mode == DownloadMode::Auto && self.prefers_clone(repo, &log))
+        {
+            cloned = true;
+            self.clone_repo(repo, &repo_path, &log).await
+        } else if let Some(selection) = &self.branches {
+            self.download_branches(repo, &repo_path, selection, &log)
+                .await
+                .map(|(bytes, present)| {
+                    branches = present;
+                    bytes
+                })
+        } else if self.latest_tag && !repo_path.exists() {
+            tag = self.latest_semver_tag(repo, &log).await;
+            match &tag {
+                Some(name) => {
+                    let url = format!("{}/archive/refs/tags/{}.zip", repo.html_url, name);
+                    self.download_and_extract(&url, &repo_path, target, &log)
+                        .await
+                }
+                None => {
+                    self.download_default_branch(repo, &repo_path, target, &log)
+                        .await
+                }
+            }
+        } else {
+            self.download_default_branch(repo, &repo_path, target, &log)
+                .await
+        };
+        let result = match result {
+            Err(e) if self.mode == DownloadMode::Auto && !cloned && e.is_missing_archive() => {
+                log.event(format!("no archive ({}), cloning instead", e));
+                cloned = true;
+                tag = None;
+                branches.clear();
+                self.clone_repo(repo, &repo_path, &log).await
+            }
+            result => result,
+        };
+        // Extraction may have given the directory a --ref-in-dirname name.
+        let repo_path = match &self.ref_dirs {
+            Some(ref_dirs) => ref_dirs.resolve(&plain_path),
+            None => repo_path,
+        };
+        match &result {
+            Ok(bytes) => log.event(format!("completed: {} bytes on disk", bytes)),
+            Err(e) => log.event(format!("failed: {}", e)),
+        }
+        let mirror = match &self.mirror_to {
+            Some(template) if result.is_ok() && cloned => {
+                Some(mirror_push(repo, &repo_path, template, self.token.as_deref(), &log).await)
+            }
+            _ => None,
+        };
+
+        if self.per_repo_log {
+            // Failed repositories may have no directory to hold the log, so it
+            // goes next to where the directory would have been.
+            let log_path = if repo_path.is_dir() {
+                repo_path.join(REPO_LOG_FILE)
+            } else {
+                PathBuf::from(format!("{}{}", repo_path.to_string_lossy(), REPO_LOG_FILE))
+            };
+            if let Err(e) = log.write(&log_path) {
+                eprintln!("WARNING: Could not write {}: {}", log_path.display(), e);
+            }
+        }
+
+        if self.trial_run && result.is_err() {
+            fs::remove_dir_all(&repo_path).ok();
+        }
+        let commit = self
+            .archive_commits
+            .lock()
+            .unwrap()
+            .remove(&repo.full_name)
+            .filter(|_| branches.is_empty());
+        let bytes = result?;
+        let bundle = match target {
+            ArchiveTarget::Bundle(bundles) => bundles.locate(&repo_path).map(|(name, _)| name),
+            ArchiveTarget::Directory => None,
+        };
+
+        let signature = if self.verify_signatures && self.branches.is_none() {
+            let git_ref = tag
+                .as_deref()
+                .or(pinned_commit(self.pinned.as_deref(), repo))
+                .unwrap_or(&repo.default_branch);
+            self.commit_signature(repo, git_ref, &log).await
+        } else {
+            None
+        };
+
+        let language_bytes = if self.language_breakdown {
+            self.language_breakdown(repo, &log).await
+        } else {
+            BTreeMap::new()
+        };
+
+        // A bare clone's layout is git's, not the project's.
+        let tree_depth = if bundle.is_none() && !cloned {
+            dir_stats(&repo_path).ok().map(|(_, depth)| depth)
+        } else {
+            None
+        };
+
+        let detected_language = if self.language_detect && repo.language.is_none() {
+            detect_language(&repo_path)
+        } else {
+            None
+        };
+        if self.trial_run {
+            fs::remove_dir_all(&repo_path).ok();
+            log.event("trial extraction verified, discarded");
+        }
+
+        Ok(Download {
+            bytes,
+            detected_language,
+            language_bytes,
+            tag,
+            branches,
+            bundle,
+            signature,
+            mirror,
+            cloned: cloned && self.mode == DownloadMode::Auto,
+            duplicate_of: self
+                .archives
+                .as_ref()
+                .and_then(|archives| archives.duplicate_of(&repo_path)),
+            tree_depth,
+            commit,
+            dir: repo_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .filter(|name| *name != repo.name),
+            conflicts_with: None,
+        })
+    }
+
+    /// `--mode auto`'s choice before any probe: history was asked for with
+    /// `--depth`, or the repository is empty, which GitHub serves no
+    /// archive for. Any other repository tries the archive first.
+    fn prefers_clone(&self, repo: &RepoInfo, log: &RepoLog) -> bool {
+        let reason = if self.depth.is_some() {
+            "--depth asks for history"
+        } else if repo.size == 0 {
+            "repository is empty"
+        } else {
+            return false;
+        };
+        log.event(format!("cloning: {}", reason));
+        true
+    }
+
+    /// `--mode clone`: a bare `git clone --mirror` into the repository
+    /// directory, skipped when it already exists like an extracted archive.
+    /// A failed clone leaves nothing behind for the next run to mistake for
+    /// a complete one.
+    async fn clone_repo(
+        &self,
+        repo: &RepoInfo,
+        repo_path: &Path,
+        log: &RepoLog,
+    ) -> Result<u64, DownloadError> {
+        if repo_path.exists()
+            && let Ok(size) = get_dir_size(repo_path)
+        {
+            log.event("already present, skipping clone");
+            return Ok(size);
+        }
+
+        log.event(format!("git clone --mirror {}", repo.clone_url));
+        let mut command = git_command(self.token.as_deref());
+        command.args(["clone", "--mirror", "--quiet"]);
+        if let Some(depth) = self.depth {
+            command.arg(format!("--depth={}", depth));
+        }
+        command.arg(&repo.clone_url).arg(repo_path);
+        if let Err(e) = run_git(command).await {
+            fs::remove_dir_all(repo_path).ok();
+            log.event(format!("clone failed: {}", e));
+            return Err(DownloadError::Failed(format!("git clone failed: {}", e)));
+        }
+
+        Ok(get_dir_size(repo_path).unwrap_or(0))
+    }
+
+    /// Signature verification of `git_ref`'s head commit. A failed lookup is
+    /// only logged: provenance is informational and never fails a download.
+    async fn commit_signature(
+        &self,
+        repo: &RepoInfo,
+        git_ref: &str,
+        log: &RepoLog,
+    ) -> Option<CommitSignature> {
+        let url = format!(
+            "{}/repos/{}/commits/{}",
+            GITHUB_API_BASE, repo.full_name, git_ref
+        );
+        let lookup = async {
+            let response = self
+                .retry_request(Some(log), || self.client.get(&url))
+                .await?;
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()));
+            }
+            response
+                .json::<CommitInfo>()
+                .await
+                .map_err(|e| e.to_string())
+        };
+
+        match lookup.await {
+            Ok(info) => {
+                let verification = info.commit.verification;
+                log.event(format!(
+                    "head commit {} of {}: signature {}",
+                    info.sha, git_ref, verification.reason
+                ));
+                Some(CommitSignature {
+                    git_ref: git_ref.to_string(),
+                    sha: info.sha,
+                    verified: verification.verified,
+                    reason: verification.reason,
+                })
+            }
+            Err(e) => {
+                log.event(format!(
+                    "could not check the signature of {}: {}",
+                    git_ref, e
+                ));
+                None
+            }
+        }
+    }
+
+    /// `--account-metadata` entry for `repo`. Only branch protection needs a
+    /// request; when it fails, for lack of scope or otherwise, the entry goes
+    /// without it.
+    async fn account_repo(&self, repo: &RepoInfo) -> AccountRepo {
+        let url = format!(
+            "{}/repos/{}/branches/{}/protection",
+            GITHUB_API_BASE, repo.full_name, repo.default_branch
+        );
+        let branch_protection = match self.retry_request(None, || self.client.get(&url)).await {
+            Ok(response) if response.status().is_success() => response.json().await.ok(),
+            _ => None,
+        };
+
+        AccountRepo {
+            full_name: repo.full_name.clone(),
+            html_url: repo.html_url.clone(),
+            default_branch: repo.default_branch.clone(),
+            description: repo.description.clone(),
+            homepage: repo.homepage.clone(),
+            topics: repo.topics.clone(),
+            visibility: repo.visibility.clone(),
+            fork: repo.is_fork,
+            branch_protection,
+        }
+    }
+
+    /// `repo`'s bytes per language as GitHub's linguist counts them. Like the
+    /// signature, a failed lookup is only logged and leaves the breakdown
+    /// empty.
+    async fn language_breakdown(&self, repo: &RepoInfo, log: &RepoLog) -> BTreeMap<String, u64> {
+        let url = format!("{}/repos/{}/languages", GITHUB_API_BASE, repo.full_name);
+        let lookup = async {
+            let response = self
+                .retry_request(Some(log), || self.client.get(&url))
+                .await?;
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()));
+            }
+            response
+                .json::<BTreeMap<String, u64>>()
+                .await
+                .map_err(|e| e.to_string())
+        };
+
+        match lookup.await {
+            Ok(languages) => {
+                log.event(format!("{} languages reported", languages.len()));
+                languages
+            }
+            Err(e) => {
+                log.event(format!("could not fetch the language breakdown: {}", e));
+                BTreeMap::new()
+            }
+        }
+    }
+
+    /// Highest stable semver tag of `repo`, accepting an optional `v`
+    /// prefix. `None` (no parseable tags, or the tag list could not be
+    /// fetched) sends the caller back to the default branch.
+    async fn latest_semver_tag(&self, repo: &RepoInfo, log: &RepoLog) -> Option<String> {
+        let tags = match self.fetch_refs(&repo.full_name, "tags").await {
+            Ok(tags) => tags,
+            Err(e) => {
+                log.event(format!("could not list tags ({}), using default branch", e));
+                return None;
+            }
+        };
+
+        let latest = tags
+            .into_iter()
+            .filter_map(|tag| {
+                let version = semver::Version::parse(tag.name.trim_start_matches('v')).ok()?;
+                version.pre.is_empty().then_some((version, tag.name))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map(|(_, name)| name);
+
+        match &latest {
+            Some(name) => log.event(format!("latest semver tag {}", name)),
+            None => log.event("no semver tags, using default branch"),
+        }
+        latest
+    }
+
+    /// Whether `branch` exists, per the branches endpoint. Only a 404 says
+    /// no; when the API cannot be asked, the archive gets its chance.
+    async fn branch_exists(&self, repo: &RepoInfo, branch: &str, log: &RepoLog) -> bool {
+        let url = format!(
+            "{}/repos/{}/branches/{}",
+            GITHUB_API_BASE, repo.full_name, branch
+        );
+        match self
+            .retry_request(Some(log), || self.client.get(&url))
+            .await
+        {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                log.event(format!("default branch {} not found", branch));
+                false
+            }
+            Ok(_) => true,
+            Err(e) => {
+                log.event(format!("could not confirm branch {}: {}", branch, e));
+                true
+            }
+        }
+    }
+
+    /// Branches to try when the default branch's archive fails: the
+    /// repository's actual branches, most recently committed to first.
+    /// Dating a branch costs a request, so only the first
+    /// `BRANCH_FALLBACK_LIMIT` listed are considered.
+    async fn fallback_branches(&self, repo: &RepoInfo, log: &RepoLog) -> Vec<String> {
+        let branches = match self.fetch_refs(&repo.full_name, "branches").await {
+            Ok(branches) => branches,
+            Err(e) => {
+                log.event(format!("could not list branches to fall back to: {}", e));
+                return Vec::new();
+            }
+        };
+
+        let mut dated = Vec::new();
+        for branch in branches
+            .into_iter()
+            .filter(|branch| branch.name != repo.default_branch)
+            .take(BRANCH_FALLBACK_LIMIT)
+        {
+            let url = format!(
+                "{}/repos/{}/commits/{}",
+                GITHUB_API_BASE, repo.full_name, branch.name
+            );
+            let date = match self
+                .retry_request(Some(log), || self.client.get(&url))
+                .await
+            {
+                Ok(response) if response.status().is_success() => response
+                    .json::<CommitInfo>()
+                    .await
+                    .ok()
+                    .and_then(|info| info.commit.committer)
+                    .and_then(|committer| committer.date),
+                _ => None,
+            };
+            dated.push((date, branch.name));
+        }
+        // Undated branches sort last, in listing order.
+        dated.sort_by(|a, b| b.0.cmp(&a.0));
+        dated.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// Lists a repository's `tags` or `branches`, across all pages.
+    async fn fetch_refs(&self, full_name: &str, kind: &str) -> Result<Vec<RefInfo>, String> {
+        let mut refs = Vec::new();
+        let mut page = 1;
+
+        loop {
+            let url = format!(
+                "{}/repos/{}/{}?per_page=100&page={}",
+                GITHUB_API_BASE, full_name, kind, page
+            );
+
+            let response = self.retry_request(None, || self.client.get(&url)).await?;
+
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()));
+            }
+
+            let data: Vec<RefInfo> = response.json().await.map_err(|e| e.to_string())?;
+
+            if data.is_empty() {
+                break;
+            }
+
+            refs.extend(data);
+            page += 1;
+        }
+
+        Ok(refs)
+    }
+
+    /// Fetches each selected branch into `{repo}/{branch}/`, skipping ones
+    /// already on disk and, with a warning, ones that cannot be fetched.
+    /// Fails only when no branch ends up present.
+    async fn download_branches(
+        &self,
+        repo: &RepoInfo,
+        repo_path: &Path,
+        selection: &BranchSelection,
+        log: &RepoLog,
+    ) -> Result<(u64, Vec<String>), DownloadError> {
+        let names = match selection {
+            BranchSelection::List(names) => names.clone(),
+            BranchSelection::All => self
+                .fetch_refs(&repo.full_name, "branches")
+                .await?
+                .into_iter()
+                .map(|branch| branch.name)
+                .filter(|name| is_safe_branch_dir(name))
+                .collect(),
+        };
+
+        let mut total = 0;
+        let mut present = Vec::new();
+        let mut last_error = None;
+        for name in names {
+            let branch_path = repo_path.join(&name);
+            if branch_path.exists()
+                && let Ok(size) = get_dir_size(&branch_path)
+            {
+                log.event(format!("branch {} already present", name));
+                total += size;
+                present.push(name);
+                continue;
+            }
+
+            if let Some(parent) = branch_path.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            log.event(format!("branch {}", name));
+            let url = format!("{}/archive/refs/heads/{}.zip", repo.html_url, name);
+            match self
+                .download_and_extract(&url, &branch_path, ArchiveTarget::Directory, log)
+                .await
+            {
+                Ok(size) => {
+                    total += size;
+                    present.push(name);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "WARNING: Skipping branch {} of {}: {}",
+                        name, repo.full_name, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if present.is_empty() => Err(e),
+            None if present.is_empty() => Err("no branches to download".to_string().into()),
+            _ => Ok((total, present)),
+        }
+    }
+
+    /// `--from-lock`: the commit archive of the repository's pinned commit,
+    /// so the tree is the locked run's however far its branches have moved.
+    /// A repository already on disk is kept, like the default branch's.
+    async fn download_pinned(
+        &self,
+        repo: &RepoInfo,
+        locked: &[LockedRepo],
+        repo_path: &Path,
+        target: ArchiveTarget<'_>,
+        log: &RepoLog,
+    ) -> Result<u64, DownloadError> {
+        let Some(commit) = pinned_commit(Some(locked), repo) else {
+            return Err("not pinned in the lock file".to_string().into());
+        };
+        if repo_path.exists()
+            && let Ok(size) = get_dir_size(repo_path)
+        {
+            log.event("already present, skipping download");
+            return Ok(size);
+        }
+
+        log.event(format!("pinned at {}", commit));
+        let url = format!("{}/archive/{}.zip", repo.html_url, commit);
+        match self
+            .download_and_extract(&url, repo_path, target, log)
+            .await
+        {
+            Err(e) if e.is_missing_archive() => Err(DownloadError::NotFound(format!(
+                "pinned commit {} is gone ({})",
+                commit, e
+            ))),
+            result => result,
+        }
+    }
+
+    async fn download_default_branch(
+        &self,
+        repo: &RepoInfo,
+        repo_path: &Path,
+        target: ArchiveTarget<'_>,
+        log: &RepoLog,
+    ) -> Result<u64, DownloadError> {
+        if repo_path.exists()
+            && let Ok(size) = get_dir_size(repo_path)
+        {
+            log.event("already present, skipping download");
+            return Ok(size);
+        }
+
+        let branch = &repo.default_branch;
+        let zip_url = format!("{}/archive/refs/heads/{}.zip", repo.html_url, branch);
+
+        let confirmed = self.branch_strategy != BranchStrategy::Api
+            || self.branch_exists(repo, branch, log).await;
+        let result = if confirmed {
+            self.download_and_extract(&zip_url, repo_path, target, log)
+                .await
+        } else {
+            Err(DownloadError::Failed(format!(
+                "default branch {} not found",
+                branch
+            )))
+        };
+
+        match result {
+            Ok(size) => Ok(size),
+            // The branch exists and was transferring; another branch won't help.
+            Err(e @ DownloadError::Stalled(_)) => Err(e),
+            Err(e) if self.branch_strategy == BranchStrategy::Metadata => {
+                Err(e.context("Failed to download"))
+            }
+            Err(e) => {
+                for fallback in self.fallback_branches(repo, log).await {
+                    let fallback_url =
+                        format!("{}/archive/refs/heads/{}.zip", repo.html_url, fallback);
+
+                    log.event(format!("falling back to branch {}", fallback));
+                    if let Ok(size) = self
+                        .download_and_extract(&fallback_url, repo_path, target, log)
+                        .await
+                    {
+                        return Ok(size);
+                    }
+                }
+
+                Err(e.context("Failed to download"))
+            }
+        }
+    }
+
+    async fn download_and_extract(
+        &self,
+        url: &str,
+        repo_path: &Path,
+        target: ArchiveTarget<'_>,
+        log: &RepoLog,
+    ) -> Result<u64, DownloadError> {
+        if self.stream_tarball && matches!(target, ArchiveTarget::Directory) {
+            return self.stream_tarball(url, repo_path, log).await;
+        }
+        let download_permit = match &self.inflight_budget {
+            Some(budget) => Some(budget.admit_download(log).await),
+            None => None,
+        };
+        let cache_entry = match &self.archive_cache {
+            Some(cache) => self
+                .archive_cache_entry(cache, url, log)
+                .await
+                .map(|entry| (cache, entry)),
+            None => None,
+        };
+        let restored = match &cache_entry {
+            Some((cache, entry)) => cache.restore(entry, repo_path, log),
+            None => None,
+        };
+        let zip_file = match restored {
+            Some(zip_file) => zip_file,
+            None => {
+                let mut stalls = 0;
+                let fetch_started = Instant::now();
+                let zip_file = loop {
+                    let fetched = if self.speculative_retry {
+                        self.fetch_racing(url, repo_path, log).await
+                    } else {
+                        self.fetch_archive(url, repo_path, log).await
+                    };
+                    match fetched {
+                        Err(DownloadError::Stalled(e)) if stalls < self.max_idle_retries => {
+                            stalls += 1;
+                            log.event(format!(
+                                "{}; resuming (idle retry {}/{})",
+                                e, stalls, self.max_idle_retries
+                            ));
+                        }
+                        Err(DownloadError::Stalled(e)) if stalls > 0 => {
+                            return Err(DownloadError::Stalled(format!(
+                                "{} (gave up after {} idle retries)",
+                                e, stalls
+                            )));
+                        }
+                        result => break result?,
+                    }
+                };
+                self.tail.record(fetch_started.elapsed());
+                if let Some((cache, entry)) = &cache_entry {
+                    cache.store(&zip_file, entry, log);
+                }
+                zip_file
+            }
+        };
+
+        let ref_dir = match (&self.ref_dirs, target) {
+            (Some(_), ArchiveTarget::Directory) => ref_dir_name(&zip_file, repo_path),
+            _ => None,
+        };
+        let commit = fs::File::open(&zip_file)
+            .ok()
+            .and_then(|file| ZipArchive::new(file).ok())
+            .and_then(|archive| archive_commit(&archive).map(str::to_string));
+        if let Some(commit) = commit {
+            self.archive_commits
+                .lock()
+                .unwrap()
+                .insert(log.repo.clone(), commit);
+        }
+        let archive_hash = match (&self.archives, target) {
+            (Some(_), ArchiveTarget::Directory) => archive_hash(&zip_file).ok(),
+            _ => None,
+        };
+        if let (Some(archives), Some(hash)) = (&self.archives, archive_hash)
+            && archives.link(hash, repo_path, log)
+        {
+            let (_, state_path) = partial_paths(repo_path);
+            fs::remove_file(&zip_file).ok();
+            fs::remove_file(&state_path).ok();
+            let size = get_dir_size(repo_path).unwrap_or(0);
+            self.rename_to_ref_dir(repo_path, ref_dir.as_deref(), log);
+            return Ok(size);
+        }
+
+        let _budget = match &self.extraction_budget {
+            Some(budget) => Some(budget.reserve(&zip_file, log).await),
+            None => None,
+        };
+        let _inflight = match (&self.inflight_budget, download_permit) {
+            (Some(budget), Some(permit)) => {
+                Some(budget.admit_extraction(permit, &zip_file, log).await)
+            }
+            _ => None,
+        };
+        let result = match target {
+            ArchiveTarget::Directory => {
+                extract_zip(&zip_file.to_string_lossy(), repo_path, &self.extract, log)
+                    .map(|skipped| (skipped, None))
+            }
+            ArchiveTarget::Bundle(bundles) => bundles
+                .append(repo_path, &zip_file, &self.extract, log)
+                .map(|(skipped, bytes)| (skipped, Some(bytes))),
+        };
+        let (_, state_path) = partial_paths(repo_path);
+        fs::remove_file(&zip_file).ok();
+        fs::remove_file(&state_path).ok();
+
+        match result {
+            Ok((skipped, bytes)) => {
+                self.files_skipped.fetch_add(skipped, Ordering::Relaxed);
+                let size = bytes.unwrap_or_else(|| get_dir_size(repo_path).unwrap_or(0));
+                let final_path = self.rename_to_ref_dir(repo_path, ref_dir.as_deref(), log);
+                if let (Some(archives), Some(hash)) = (&self.archives, archive_hash) {
+                    archives.insert(hash, &final_path, &log.repo);
+                }
+                Ok(size)
+            }
+            Err(e) if is_storage_full(e.as_ref()) => {
+                // A half-extracted directory would pass for a complete one
+                // next run, and removing it gives back the space or inodes.
+                if matches!(target, ArchiveTarget::Directory) {
+                    fs::remove_dir_all(repo_path).ok();
+                }
+                let message = storage_full_message(repo_path);
+                log.event(format!("extraction failed: {} ({})", message, e));
+                Err(DownloadError::Failed(message))
+            }
+            Err(e) => {
+                log.event(format!("extraction failed: {}", e));
+                Err(DownloadError::Failed(e.to_string()))
+            }
+        }
+    }
+
+    /// Renames a freshly extracted directory to its `--ref-in-dirname` name
+    /// and remembers the mapping, returning where the repository now is.
+    /// Without a name, or if the rename fails, it stays where it is.
+    fn rename_to_ref_dir(&self, repo_path: &Path, ref_dir: Option<&str>, log: &RepoLog) -> PathBuf {
+        let (Some(ref_dirs), Some(name)) = (&self.ref_dirs, ref_dir) else {
+            return repo_path.to_path_buf();
+        };
+        let named = repo_path.with_file_name(name);
+        match fs::rename(repo_path, &named) {
+            Ok(()) => {
+                log.event(format!("renamed to {}", name));
+                ref_dirs.insert(repo_path, &named);
+                named
+            }
+            Err(e) => {
+                log.event(format!("could not rename to {}: {}", name, e));
+                repo_path.to_path_buf()
+            }
+        }
+    }
+
+    /// `--cache-dir` entry for the archive at `url`, named after the commit
+    /// its ref points at now, so a moved branch misses the cache instead
+    /// of serving stale content. Costs one API request per archive; when
+    /// the lookup fails the archive is downloaded uncached.
+    async fn archive_cache_entry(
+        &self,
+        cache: &ArchiveCache,
+        url: &str,
+        log: &RepoLog,
+    ) -> Option<PathBuf> {
+        let git_ref = archive_ref(url)?;
+        let prefetched = self
+            .head_shas
+            .lock()
+            .unwrap()
+            .get(&format!("{}@{}", log.repo, git_ref))
+            .cloned();
+        if let Some(sha) = prefetched {
+            return Some(cache.entry(&log.repo, &sha));
+        }
+        // A commit archive (`--from-lock`) never changes.
+        if git_ref.len() == 40 && git_ref.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Some(cache.entry(&log.repo, git_ref));
+        }
+        let api_url = format!("{}/repos/{}/commits/{}", GITHUB_API_BASE, log.repo, git_ref);
+        let lookup = async {
+            let response = self
+                .retry_request(Some(log), || self.client.get(&api_url))
+                .await?;
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()));
+            }
+            response
+                .json::<CommitInfo>()
+                .await
+                .map_err(|e| e.to_string())
+        };
+
+        match lookup.await {
+            Ok(info) => Some(cache.entry(&log.repo, &info.sha)),
+            Err(e) => {
+                log.event(format!(
+                    "could not resolve {} for the archive cache: {}",
+                    git_ref, e
+                ));
+                None
+            }
+        }
+    }
+
+    /// Under `--speculative-retry`, races the download against a second,
+    /// fresh one started once it has become a straggler at the tail of the
+    /// pass, and keeps whichever archive arrives first. The speculative copy
+    /// goes to its own `{repo}.speculative.zip.part`, so the two never share
+    /// a file; a failure of the copy leaves the original running.
+    async fn fetch_racing(
+        &self,
+        url: &str,
+        repo_path: &Path,
+        log: &RepoLog,
+    ) -> Result<PathBuf, DownloadError> {
+        let started = Instant::now();
+        let speculative_path = PathBuf::from(format!(
+            "{}{}",
+            repo_path.to_string_lossy(),
+            SPECULATIVE_SUFFIX
+        ));
+        let (spec_part, spec_state) = partial_paths(&speculative_path);
+
+        let primary = self.fetch_archive(url, repo_path, log);
+        let speculative = async {
+            while !self.tail.is_straggler(started.elapsed()) {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            log.event("straggling at the end of the pass, starting a speculative download");
+            self.fetch_archive(url, &speculative_path, log).await
+        };
+        tokio::pin!(primary, speculative);
+
+        let winner = tokio::select! {
+            result = &mut primary => result,
+            result = &mut speculative => match result {
+                Ok(part) => {
+                    log.event("speculative download finished first");
+                    let (primary_part, primary_state) = partial_paths(repo_path);
+                    fs::remove_file(primary_part).ok();
+                    fs::remove_file(primary_state).ok();
+                    fs::remove_file(&spec_state).ok();
+                    return Ok(part);
+                }
+                Err(e) => {
+                    log.event(format!("speculative download failed: {}", e));
+                    primary.await
+                }
+            },
+        };
+
+        fs::remove_file(&spec_part).ok();
+        fs::remove_file(&spec_state).ok();
+        winner
+    }
+
+    /// Streams the archive at `url` into `{repo}.zip.part`, resuming a
+    /// previous run's partial file with a range request when its URL and
+    /// ETag still match. The part file only replaces an earlier one once the
+    /// server has answered successfully, so branch fallback probes that 404
+    /// never discard resumable progress.
+    async fn fetch_archive(
+        &self,
+        url: &str,
+        repo_path: &Path,
+        log: &RepoLog,
+    ) -> Result<PathBuf, DownloadError> {
+        let _host_permit = match &self.host_limiter {
+            Some(limiter) => Some(limiter.acquire(url).await),
+            None => None,
+        };
+        let (part_path, state_path) = partial_paths(repo_path);
+        let started = Instant::now();
+
+        let mut previous = PartialDownload::load(&state_path)
+            .filter(|state| state.url == url && state.etag.is_some() && part_path.exists());
+        let mut generating_since = None;
+        let mut polls = 0;
+
+        let (mut response, etag, resume_from) = loop {
+            let resume_from = match &previous {
+                Some(_) => fs::metadata(&part_path).map(|m| m.len()).unwrap_or(0),
+                None => 0,
+            };
+            let if_range = previous
+                .as_ref()
+                .and_then(|p| p.etag.clone())
+                .filter(|_| resume_from > 0);
+
+            log.event(format!("GET {}", url));
+            if let Some(etag) = &if_range {
+                log.event(format!(
+                    "resuming from byte {} (ETag {})",
+                    resume_from, etag
+                ));
+            }
+
+            let response = self
+                .retry_request(Some(log), || {
+                    let mut request = self.client.get(url);
+                    if let Some(etag) = &if_range {
+                        request = request
+                            .header(reqwest::header::RANGE, format!("bytes={}-", resume_from))
+                            .header(reqwest::header::IF_RANGE, etag);
+                    }
+                    request
+                })
+                .await?;
+
+            let etag = response
+                .headers()
+                .get(reqwest::header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+
+            // A partial response for a different ETag, or an offset the
+            // archive no longer covers, means the stored part is stale.
+            let stale = if_range.is_some()
+                && match response.status() {
+                    StatusCode::PARTIAL_CONTENT => etag != if_range,
+                    StatusCode::RANGE_NOT_SATISFIABLE => true,
+                    _ => false,
+                };
+            if stale {
+                log.event(format!(
+                    "partial download is stale (HTTP {}), restarting",
+                    response.status()
+                ));
+                fs::remove_file(&part_path).ok();
+                fs::remove_file(&state_path).ok();
+                previous = None;
+                continue;
+            }
+
+            log.event(format!("HTTP {}", response.status()));
+            if response.status() == StatusCode::NOT_FOUND && self.token.is_some() {
+                return Err(DownloadError::NotFound(format!(
+                    "HTTP {} (if the repository is private, check that your token grants \
+                     Contents read access to it)",
+                    response.status()
+                )));
+            }
+
+            if response.status() == StatusCode::ACCEPTED {
+                self.await_generation(&response, &mut generating_since, &mut polls, log)
+                    .await?;
+                continue;
+            }
+
+            if response.status() == StatusCode::NOT_FOUND {
+                return Err(DownloadError::NotFound(format!(
+                    "HTTP {}",
+                    response.status()
+                )));
+            }
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()).into());
+            }
+
+            let resume_from = if response.status() == StatusCode::PARTIAL_CONTENT {
+                resume_from
+            } else {
+                0
+            };
+            break (response, etag, resume_from);
+        };
+        let resuming = resume_from > 0;
+
+        let mut state = PartialDownload {
+            url: url.to_string(),
+            etag,
+            bytes_written: resume_from,
+        };
+        state.save(&state_path)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resuming)
+            .truncate(!resuming)
+            .open(&part_path)
+            .map_err(|e| e.to_string())?;
+
+        let mut last_saved = state.bytes_written;
+        loop {
+            let chunk = match self.stall_timeout {
+                Some(limit) => match tokio::time::timeout(limit, response.chunk()).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        // Keep what arrived so the retry can resume from it.
+                        file.flush().map_err(|e| e.to_string())?;
+                        state.save(&state_path)?;
+                        let message = format!(
+                            "stalled: no data for {}s at byte {}",
+                            limit.as_secs(),
+                            state.bytes_written
+                        );
+                        return Err(DownloadError::Stalled(message));
+                    }
+                },
+                None => response.chunk().await,
+            };
+            let Some(chunk) = chunk.map_err(|e| e.to_string())? else {
+                break;
+            };
+
+            file.write_all(&chunk).map_err(|e| e.to_string())?;
+            state.bytes_written += chunk.len() as u64;
+
+            if state.bytes_written - last_saved >= PARTIAL_STATE_INTERVAL {
+                if let Some(events) = &self.events {
+                    events.emit(Event::Progress {
+                        repo: &log.repo,
+                        bytes: state.bytes_written,
+                    });
+                }
+                file.flush().map_err(|e| e.to_string())?;
+                state.save(&state_path)?;
+                last_saved = state.bytes_written;
+            }
+        }
+
+        file.flush().map_err(|e| e.to_string())?;
+        state.save(&state_path)?;
+        log.event(format!(
+            "downloaded {} bytes in {} ms",
+            state.bytes_written - resume_from,
+            started.elapsed().as_millis()
+        ));
+
+        Ok(part_path)
+    }
+
+    /// Waits before asking again for an archive GitHub answered 202 for:
+    /// a success status, but its body is not the archive, which GitHub is
+    /// still generating. `since` is when the first 202 came, on tokio's
+    /// clock like the polling sleeps; past `--archive-generation-timeout`
+    /// the download fails instead.
+    async fn await_generation(
+        &self,
+        response: &reqwest::Response,
+        since: &mut Option<tokio::time::Instant>,
+        polls: &mut u32,
+        log: &RepoLog,
+    ) -> Result<(), DownloadError> {
+        let since = *since.get_or_insert_with(tokio::time::Instant::now);
+        let remaining = self
+            .archive_generation_timeout
+            .and_then(|timeout| timeout.checked_sub(since.elapsed()))
+            .filter(|remaining| !remaining.is_zero());
+        let Some(remaining) = remaining else {
+            return Err(format!(
+                "HTTP {}: archive still being generated after {}s",
+                response.status(),
+                since.elapsed().as_secs()
+            )
+            .into());
+        };
+        let delay = retry_after(response)
+            .unwrap_or_else(|| ARCHIVE_POLL_DELAY * 2_u32.pow((*polls).min(3)))
+            .min(remaining);
+        *polls += 1;
+        log.event(format!(
+            "archive being generated, polling again in {:.1}s",
+            delay.as_secs_f64()
+        ));
+        self.backoff(delay).await?;
+        Ok(())
+    }
+
+    /// Under `--stream-tarball`, downloads the ref of the zip archive at
+    /// `url` as a tarball from the API and extracts it as it arrives, never
+    /// writing an archive to disk. A stream cannot resume from a range, so
+    /// a stall starts the download over.
+    async fn stream_tarball(
+        &self,
+        url: &str,
+        repo_path: &Path,
+        log: &RepoLog,
+    ) -> Result<u64, DownloadError> {
+        let Some(git_ref) = archive_ref(url) else {
+            return Err(format!("no ref in archive URL {}", url).into());
+        };
+        let tarball_url = format!("{}/repos/{}/tarball/{}", GITHUB_API_BASE, log.repo, git_ref);
+        let mut stalls = 0;
+        let result = loop {
+            let result = self.fetch_tarball(&tarball_url, repo_path, log).await;
+            if result.is_err() {
+                // Unlike a zip, a failed stream leaves a half-extracted
+                // directory that would pass for a complete one next run.
+                fs::remove_dir_all(repo_path).ok();
+            }
+            match result {
+                Err(DownloadError::Stalled(e)) if stalls < self.max_idle_retries => {
+                    stalls += 1;
+                    log.event(format!(
+                        "{}; restarting (idle retry {}/{})",
+                        e, stalls, self.max_idle_retries
+                    ));
+                }
+                Err(DownloadError::Stalled(e)) if stalls > 0 => {
+                    break Err(DownloadError::Stalled(format!(
+                        "{} (gave up after {} idle retries)",
+                        e, stalls
+                    )));
+                }
+                result => break result,
+            }
+        };
+
+        let (skipped, commit, streamed) = result?;
+        self.files_skipped.fetch_add(skipped, Ordering::Relaxed);
+        self.archive_bytes_streamed
+            .fetch_add(streamed, Ordering::Relaxed);
+        if let Some(commit) = commit {
+            self.archive_commits
+                .lock()
+                .unwrap()
+                .insert(log.repo.clone(), commit);
+        }
+        Ok(get_dir_size(repo_path).unwrap_or(0))
+    }
+
+    /// One attempt of `stream_tarball`: returns the files skipped, the
+    /// archive's commit and the compressed bytes streamed.
+    async fn fetch_tarball(
+        &self,
+        url: &str,
+        repo_path: &Path,
+        log: &RepoLog,
+    ) -> Result<(u64, Option<String>, u64), DownloadError> {
+        let _host_permit = match &self.host_limiter {
+            Some(limiter) => Some(limiter.acquire(url).await),
+            None => None,
+        };
+        let started = Instant::now();
+        let mut generating_since = None;
+        let mut polls = 0;
+
+        let mut response = loop {
+            log.event(format!("GET {}", url));
+            let response = self
+                .retry_request(Some(log), || self.client.get(url))
+                .await?;
+            log.event(format!("HTTP {}", response.status()));
+            match response.status() {
+                StatusCode::ACCEPTED => {
+                    self.await_generation(&response, &mut generating_since, &mut polls, log)
+                        .await?;
+                }
+                StatusCode::NOT_FOUND if self.token.is_some() => {
+                    return Err(DownloadError::NotFound(format!(
+                        "HTTP {} (if the repository is private, check that your token grants \
+                         Contents read access to it)",
+                        response.status()
+                    )));
+                }
+                StatusCode::NOT_FOUND => {
+                    return Err(DownloadError::NotFound(format!(
+                        "HTTP {}",
+                        response.status()
+                    )));
+                }
+                status if !status.is_success() => {
+                    return Err(format!("HTTP {}", status).into());
+                }
+                _ => break response,
+            }
+        };
+
+        // tar reads synchronously; the body is pulled on this worker, which
+        // `block_in_place` hands its other tasks away from meanwhile.
+        let mut body = BodyReader {
+            response: &mut response,
+            runtime: tokio::runtime::Handle::current(),
+            stall_timeout: self.stall_timeout,
+            progress: self
+                .events
+                .as_deref()
+                .map(|events| (events, log.repo.as_str())),
+            chunk: Vec::new(),
+            offset: 0,
+            received: 0,
+            last_reported: 0,
+            stalled: None,
+        };
+        let extracted =
+            tokio::task::block_in_place(|| extract_tar(&mut body, repo_path, &self.extract, log));
+        if let Some(message) = body.stalled {
+            return Err(DownloadError::Stalled(message));
+        }
+        let received = body.received;
+
+        match extracted {
+            Ok((skipped, commit)) => {
+                log.event(format!(
+                    "streamed {} bytes in {} ms without a temporary archive",
+                    received,
+                    started.elapsed().as_millis()
+                ));
+                Ok((skipped, commit, received))
+            }
+            Err(e) if is_storage_full(e.as_ref()) => {
+                let message = storage_full_message(repo_path);
+                log.event(format!("extraction failed: {} ({})", message, e));
+                Err(DownloadError::Failed(message))
+            }
+            Err(e) => {
+                log.event(format!("extraction failed: {}", e));
+                Err(DownloadError::Failed(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Synchronous view of a response body for `extract_tar`. Every read that
+/// runs out of data waits on the runtime for the next chunk, so it must
+/// only be read inside `tokio::task::block_in_place`.
+struct BodyReader<'a> {
+    response: &'a mut reqwest::Response,
+    runtime: tokio::runtime::Handle,
+    stall_timeout: Option<Duration>,
+    /// Where `Progress` events go, and the repository they are for.
+    progress: Option<(&'a EventBus, &'a str)>,
+    chunk: Vec<u8>,
+    /// Bytes of `chunk` already read.
+    offset: usize,
+    received: u64,
+    last_reported: u64,
+    /// Set when the body went quiet for the whole stall timeout.
+    stalled: Option<String>,
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            let next = self.response.chunk();
+            let chunk = match self.stall_timeout {
+                Some(limit) => match self.runtime.block_on(tokio::time::timeout(limit, next)) {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        let message = format!(
+                            "stalled: no data for {}s at byte {}",
+                            limit.as_secs(),
+                            self.received
+                        );
+                        self.stalled = Some(message.clone());
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, message));
+                    }
+                },
+                None => self.runtime.block_on(next),
+            };
+            let Some(chunk) = chunk.map_err(std::io::Error::other)? else {
+                return Ok(0);
+            };
+
+            self.chunk.clear();
+            self.chunk.extend_from_slice(&chunk);
+            self.offset = 0;
+            self.received += chunk.len() as u64;
+            if let Some((events, repo)) = self.progress
+                && self.received - self.last_reported >= PARTIAL_STATE_INTERVAL
+            {
+                events.emit(Event::Progress {
+                    repo,
+                    bytes: self.received,
+                });
+                self.last_reported = self.received;
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.offset);
+        buf[..n].copy_from_slice(&self.chunk[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+/// The branch, tag or commit a `{html_url}/archive/{ref}.zip` URL is for,
+/// without its `refs/heads/` or `refs/tags/` prefix, as the API takes it.
+fn archive_ref(url: &str) -> Option<&str> {
+    let git_ref = url.rsplit_once("/archive/")?.1.strip_suffix(".zip")?;
+    Some(
+        git_ref
+            .strip_prefix("refs/heads/")
+            .or_else(|| git_ref.strip_prefix("refs/tags/"))
+            .unwrap_or(git_ref),
+    )
+}
+
+/// On-disk record of an archive download in progress, kept next to the
+/// `.part` file so a later invocation can resume it.
+#[derive(Serialize, Deserialize)]
+struct PartialDownload {
+    url: String,
+    etag: Option<String>,
+    bytes_written: u64,
+}
+
+impl PartialDownload {
+    fn load(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        fs::write(path, data).map_err(|e| e.to_string())
+    }
+}
+
+/// Where a downloaded archive ends up.
+#[derive(Clone, Copy)]
+enum ArchiveTarget<'a> {
+    /// Extracted into the repository's own directory.
+    Directory,
+    /// Copied into a grouped archive by `--compact-small-repos`.
+    Bundle(&'a SmallRepoBundles),
+}
+
+const BUNDLE_PREFIX: &str = "small-";
+/// Repositories per grouped archive before a new one is started.
+const BUNDLE_MAX_REPOS: usize = 100;
+/// Suffix of a grouped archive still being written; it is renamed to
+/// `.zip` once complete, so an interrupted run never leaves a truncated one.
+const BUNDLE_WRITING_SUFFIX: &str = ".zip.writing";
+
+/// Grouped archives written by `--compact-small-repos`, per owner
+/// directory. Each `small-NNN.zip` holds up to `BUNDLE_MAX_REPOS`
+/// repositories under `{name}/`, their entries copied from the downloaded
+/// archive without recompressing.
+struct SmallRepoBundles {
+    threshold_kb: u32,
+    roots: std::sync::Mutex<HashMap<PathBuf, RootBundles>>,
+}
+
+struct RootBundles {
+    next_index: usize,
+    current: Option<OpenBundle>,
+    /// Bundled repository name to its archive and uncompressed bytes.
+    bundled: HashMap<String, (String, u64)>,
+}
+
+struct OpenBundle {
+    name: String,
+    writer: zip::ZipWriter<fs::File>,
+    repos: usize,
+}
+
+impl SmallRepoBundles {
+    fn new(threshold_kb: u32) -> Self {
+        Self {
+            threshold_kb,
+            roots: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Archive already holding `repo_path`'s repository, and its size.
+    fn locate(&self, repo_path: &Path) -> Option<(String, u64)> {
+        let (root, name) = split_repo_path(repo_path);
+        let mut roots = self.roots.lock().unwrap();
+        let bundles = roots
+            .entry(root.to_path_buf())
+            .or_insert_with(|| RootBundles::scan(root));
+        bundles.bundled.get(&name).cloned()
+    }
+
+    /// Copies the wanted entries of the downloaded `zip_path` into the open
+    /// grouped archive, returning the files skipped and the bytes added.
+    fn append(
+        &self,
+        repo_path: &Path,
+        zip_path: &Path,
+        options: &ExtractOptions,
+        log: &RepoLog,
+    ) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        let (root, name) = split_repo_path(repo_path);
+        let mut source = ZipArchive::new(fs::File::open(zip_path)?)?;
+
+        let mut roots = self.roots.lock().unwrap();
+        let bundles = roots
+            .entry(root.to_path_buf())
+            .or_insert_with(|| RootBundles::scan(root));
+        if bundles.current.is_none() {
+            let bundle = format!("{}{:03}", BUNDLE_PREFIX, bundles.next_index);
+            let file = fs::File::create(root.join(format!("{}{}", bundle, BUNDLE_WRITING_SUFFIX)))?;
+            bundles.next_index += 1;
+            bundles.current = Some(OpenBundle {
+                name: format!("{}.zip", bundle),
+                writer: zip::ZipWriter::new(file),
+                repos: 0,
+            });
+        }
+        let current = bundles.current.as_mut().unwrap();
+
+        let mut skipped = 0;
+        let mut written = 0;
+        let mut bytes = 0;
+        for i in 0..source.len() {
+            let file = source.by_index_raw(i)?;
+            let Some(path) = file.enclosed_name() else {
+                log.event(format!("skipped unsafe path {}", file.name()));
+                continue;
+            };
+            let Some(stripped) = options.strip(path) else {
+                continue;
+            };
+            let inner: Vec<String> = stripped
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            let mut entry = format!("{}/{}", name, inner.join("/"));
+
+            if file.is_dir() {
+                entry.push('/');
+            } else if !options.wants(path) {
+                log.event(format!("skipped {} (extension not allowed)", file.name()));
+                skipped += 1;
+                continue;
+            } else {
+                bytes += file.size();
+                written += 1;
+            }
+            current.writer.raw_copy_file_rename(file, entry)?;
+        }
+
+        current.repos += 1;
+        log.event(format!(
+            "added {} files to {}, skipped {}",
+            written, current.name, skipped
+        ));
+        bundles.bundled.insert(name, (current.name.clone(), bytes));
+        if current.repos >= BUNDLE_MAX_REPOS {
+            bundles.close(root)?;
+        }
+        Ok((skipped, bytes))
+    }
+
+    /// Completes every open grouped archive; called at the end of a pass.
+    fn finish(&self) -> Result<(), String> {
+        let mut roots = self.roots.lock().unwrap();
+        for (root, bundles) in roots.iter_mut() {
+            bundles.close(root)?;
+        }
+        Ok(())
+    }
+}
+
+impl RootBundles {
+    /// Indexes the complete grouped archives already in `root`, so their
+    /// repositories are skipped and numbering continues after them.
+    fn scan(root: &Path) -> Self {
+        let mut bundles = Self {
+            next_index: 1,
+            current: None,
+            bundled: HashMap::new(),
+        };
+        let Ok(entries) = fs::read_dir(root) else {
+            return bundles;
+        };
+
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some(index) = file_name
+                .strip_prefix(BUNDLE_PREFIX)
+                .and_then(|rest| rest.strip_suffix(".zip"))
+                .and_then(|index| index.parse::<usize>().ok())
+            else {
+                continue;
+            };
+            bundles.next_index = bundles.next_index.max(index + 1);
+
+            let Ok(file) = fs::File::open(entry.path()) else {
+                continue;
+            };
+            let Ok(mut archive) = ZipArchive::new(file) else {
+                continue;
+            };
+            for i in 0..archive.len() {
+                let Ok(file) = archive.by_index_raw(i) else {
+                    continue;
+                };
+                let Some(name) = file.name().split('/').next() else {
+                    continue;
+                };
+                let size = file.size();
+                let (_, bytes) = bundles
+                    .bundled
+                    .entry(name.to_string())
+                    .or_insert_with(|| (file_name.clone(), 0));
+                *bytes += size;
+            }
+        }
+        bundles
+    }
+
+    fn close(&mut self, root: &Path) -> Result<(), String> {
+        let Some(mut bundle) = self.current.take() else {
+            return Ok(());
+        };
+        bundle.writer.finish().map_err(|e| e.to_string())?;
+        let stem = bundle.name.trim_end_matches(".zip");
+        fs::rename(
+            root.join(format!("{}{}", stem, BUNDLE_WRITING_SUFFIX)),
+            root.join(&bundle.name),
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Splits `{root}/{name}` into the owner directory and repository name.
+fn split_repo_path(repo_path: &Path) -> (&Path, String) {
+    let root = repo_path.parent().unwrap_or(Path::new(""));
+    let name = repo_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    (root, name)
+}
+
+fn partial_paths(repo_path: &Path) -> (PathBuf, PathBuf) {
+    let base = repo_path.to_string_lossy();
+    (
+        PathBuf::from(format!("{}.zip.part", base)),
+        PathBuf::from(format!("{}.zip.part.json", base)),
+    )
+}
+
+/// Drops partial-download leftovers that can no longer be resumed (missing
+/// part file or state, or a repository that has since been extracted) and
+/// returns how many resumable downloads remain.
+fn reconcile_partial_downloads(dir: &Path) -> usize {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    let mut resumable = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if let Some(repo) = name.strip_suffix(".zip.part.json") {
+            let repo_path = dir.join(repo);
+            let (part_path, _) = partial_paths(&repo_path);
+            // Speculative copies are never resumed; their original is.
+            let valid = part_path.exists()
+                && !repo_path.exists()
+                && !repo.ends_with(SPECULATIVE_SUFFIX)
+                && PartialDownload::load(&path).is_some();
+
+            if valid {
+                resumable += 1;
+            } else {
+                fs::remove_file(&path).ok();
+                fs::remove_file(&part_path).ok();
+            }
+        } else if let Some(repo) = name.strip_suffix(".zip.part") {
+            let (_, state_path) = partial_paths(&dir.join(repo));
+            if !state_path.exists() {
+                fs::remove_file(&path).ok();
+            }
+        }
+    }
+
+    resumable
+}
+
+/// Holds off the next download while free space at `dir` is below
+/// `min_free` bytes. Partial archives of repositories not downloading right
+/// now go first, since losing one only costs a restart; if that is not
+/// enough, waits for running downloads or the operator to free space.
+async fn wait_for_free_space(
+    dir: &Path,
+    min_free: u64,
+    roots: &[PathBuf],
+    active: &std::sync::Mutex<HashSet<PathBuf>>,
+    quiet: bool,
+) {
+    if fs2::available_space(dir).map_or(true, |free| free >= min_free) {
+        return;
+    }
+
+    let freed: u64 = {
+        let active = active.lock().unwrap();
+        roots
+            .iter()
+            .map(|root| remove_idle_partials(root, &active))
+            .sum()
+    };
+    if freed > 0 {
+        status!(
+            quiet,
+            "Low disk space: removed {} of paused partial downloads",
+            format_size(freed)
+        );
+    }
+
+    let mut warned = false;
+    while fs2::available_space(dir).is_ok_and(|free| free < min_free) {
+        if !warned {
+            status!(
+                quiet,
+                "Low disk space at {}: waiting until {} are free",
+                dir.display(),
+                format_size(min_free)
+            );
+            warned = true;
+        }
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+}
+
+/// Deletes partial archives in `dir` that no running download is writing,
+/// returning the bytes freed.
+fn remove_idle_partials(dir: &Path, active: &HashSet<PathBuf>) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    let mut freed = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(repo) = name.strip_suffix(".zip.part") else {
+            continue;
+        };
+        let repo_path = dir.join(repo);
+        let original = dir.join(repo.strip_suffix(SPECULATIVE_SUFFIX).unwrap_or(repo));
+        if active.contains(&original) {
+            continue;
+        }
+
+        let (part_path, state_path) = partial_paths(&repo_path);
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        if fs::remove_file(&part_path).is_ok() {
+            freed += size;
+        }
+        fs::remove_file(&state_path).ok();
+    }
+
+    freed
+}
+
+type TransportFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<reqwest::Response, reqwest::Error>> + Send + 'a>,
+>;
+
+/// The network boundary every request goes through. Production uses the
+/// reqwest clients directly; `Config::transport` swaps in another one, such
+/// as the scripted transport the tests drive the retry and backoff paths
+/// with. Requests are built by `Scraper::client`, whose default headers are
+/// added by the client that executes them.
+trait Transport: Send + Sync {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_>;
+}
+
+impl Transport for Client {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        Box::pin(Client::execute(self, request))
+    }
+}
+
+/// Delay before retrying after failed attempt `attempt` (zero-based):
+/// `RETRY_DELAY_MS * 2^attempt`, i.e. 1s then 2s with the defaults. Returns
+/// `None` after the final of `attempts`, when there is nothing left to wait
+/// for.
+fn backoff_delay(attempt: u32, attempts: u32) -> Option<Duration> {
+    if attempt + 1 >= attempts {
+        return None;
+    }
+
+    Some(Duration::from_millis(RETRY_DELAY_MS * 2_u64.pow(attempt)))
+}
+
+/// First wait between polls of an archive that is being generated; it
+/// doubles up to eight times this unless GitHub sends `Retry-After`.
+const ARCHIVE_POLL_DELAY: Duration = Duration::from_secs(1);
+
+/// `Retry-After` in its delay-seconds form; GitHub does not send dates.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn is_rate_limited(response: &reqwest::Response) -> bool {
+    let headers = response.headers();
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => true,
+        StatusCode::FORBIDDEN => {
+            headers.contains_key(reqwest::header::RETRY_AFTER)
+                || headers
+                    .get("x-ratelimit-remaining")
+                    .is_some_and(|v| v.as_bytes() == b"0")
+        }
+        _ => false,
+    }
+}
+
+struct Filters {
+    language: Option<String>,
+    min_stars: u32,
+    max_size: Option<u32>,
+    only_original: bool,
+    regex: Option<Regex>,
+    /// Lowercased `--name-contains` values.
+    name_contains: Vec<String>,
+    name_contains_all: bool,
+    /// Patterns fetched from `--repo-blocklist-url`.
+    blocklist: Vec<BlockPattern>,
+}
+
+/// One `--repo-blocklist-url` line: `owner/name` or a bare name, where `*`
+/// and `?` match within a single path segment. Matching ignores case, as
+/// GitHub does.
+struct BlockPattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl BlockPattern {
+    fn parse(pattern: &str) -> Self {
+        let mut regex = String::from("(?i)^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&regex).unwrap(),
+        }
+    }
+
+    fn matches(&self, repo: &RepoInfo) -> bool {
+        if self.pattern.contains('/') {
+            self.regex.is_match(&repo.full_name)
+        } else {
+            self.regex.is_match(&repo.name)
+        }
+    }
+}
+
+/// How long a fetched blocklist is reused before the URL is asked again.
+const BLOCKLIST_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Where the last copy of the blocklist at `url` is kept.
+fn blocklist_cache_path(url: &str) -> PathBuf {
+    let digest = Sha256::digest(url.as_bytes());
+    let name: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    std::env::temp_dir().join(format!("magnet-blocklist-{}.txt", name))
+}
+
+fn parse_blocklist(text: &str) -> Vec<BlockPattern> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(BlockPattern::parse)
+        .collect()
+}
+
+impl Filters {
+    /// Returns why `repo` is rejected by the active filters, or `None` if it
+    /// passes all of them.
+    fn exclusion_reason(&self, repo: &RepoInfo) -> Option<String> {
+        if let Some(blocked) = self.blocklist.iter().find(|p| p.matches(repo)) {
+            return Some(format!(
+                "matches blocklist pattern {} from --repo-blocklist-url",
+                blocked.pattern
+            ));
+        }
+
+        if self.only_original && repo.is_fork {
+            return Some("repository is a fork and --only-original is set".to_string());
+        }
+
+        if repo.stars < self.min_stars {
+            return Some(format!(
+                "{} stars is below --min-stars {}",
+                repo.stars, self.min_stars
+            ));
+        }
+
+        if let Some(max_size_mb) = self.max_size
+            && repo.size > max_size_mb * 1024
+        {
+            return Some(format!(
+                "size {} KB exceeds --max-size {}MB",
+                repo.size, max_size_mb
+            ));
+        }
+
+        if let Some(lang_filter) = &self.language {
+            match &repo.language {
+                Some(lang) => {
+                    if lang.to_lowercase() != lang_filter.to_lowercase() {
+                        return Some(format!(
+                            "language {} does not match --language {}",
+                            lang, lang_filter
+                        ));
+                    }
+                }
+                None => {
+                    return Some(format!(
+                        "no language reported, --language {} requires one",
+                        lang_filter
+                    ));
+                }
+            }
+        }
+
+        if let Some(regex) = &self.regex
+            && !regex.is_match(&repo.name)
+        {
+            return Some(format!("name does not match --regex {}", regex.as_str()));
+        }
+
+        if !self.name_contains.is_empty() {
+            let name = repo.name.to_lowercase();
+            let contains = |needle: &String| name.contains(needle.as_str());
+            let matched = if self.name_contains_all {
+                self.name_contains.iter().all(contains)
+            } else {
+                self.name_contains.iter().any(contains)
+            };
+            if !matched {
+                return Some(format!(
+                    "name does not contain {} of --name-contains {}",
+                    if self.name_contains_all { "all" } else { "any" },
+                    self.name_contains.join(", ")
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+/// What `--rename-on-conflict` does with a repository whose directory
+/// another one in the pass has already claimed.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum ConflictPolicy {
+    /// `name-2`, `name-3`, ...: never overwrites, so it is the default.
+    #[default]
+    Suffix,
+    /// `owner-name`, suffixed in turn if that is taken too.
+    UserPrefix,
+    /// Leaves the later repository out with a warning.
+    Skip,
+}
+
+/// Where `DirClaims::claim` puts a repository.
+#[derive(Debug, PartialEq)]
+enum Claim {
+    /// Its own name was free.
+    Own,
+    Renamed {
+        dir: String,
+        conflicts_with: String,
+    },
+    Skipped {
+        conflicts_with: String,
+    },
+}
+
+/// Repository directories claimed so far in a pass. Paths are compared
+/// lowercased: `download <list>` may name the same account in two
+/// spellings, and on a case-insensitive filesystem `Foo/utils` and
+/// `foo/utils` are one directory that two downloads would write at once.
+#[derive(Default)]
+struct DirClaims {
+    /// Lowercased path to the repository that claimed it.
+    claimed: HashMap<String, String>,
+}
+
+impl DirClaims {
+    fn claim(&mut self, root: &Path, repo: &RepoInfo, policy: ConflictPolicy) -> Claim {
+        let Some(holder) = self.holder(root, &repo.name) else {
+            self.insert(root, &repo.name, repo);
+            return Claim::Own;
+        };
+        let conflicts_with = holder.to_string();
+        let base = match policy {
+            ConflictPolicy::Skip => return Claim::Skipped { conflicts_with },
+            ConflictPolicy::Suffix => repo.name.clone(),
+            ConflictPolicy::UserPrefix => format!("{}-{}", repo.owner(), repo.name),
+        };
+        let dir = std::iter::once(base.clone())
+            .chain((2..).map(|n| format!("{}-{}", base, n)))
+            .find(|dir| self.holder(root, dir).is_none())
+            .unwrap();
+        self.insert(root, &dir, repo);
+        Claim::Renamed {
+            dir,
+            conflicts_with,
+        }
+    }
+
+    fn holder(&self, root: &Path, dir: &str) -> Option<&str> {
+        let key = root.join(dir).to_string_lossy().to_lowercase();
+        self.claimed.get(&key).map(String::as_str)
+    }
+
+    fn insert(&mut self, root: &Path, dir: &str, repo: &RepoInfo) {
+        let key = root.join(dir).to_string_lossy().to_lowercase();
+        self.claimed.insert(key, repo.full_name.clone());
+    }
+}
+
+/// Picks the `--split-dirs` destination for a repository. FNV-1a over the
+/// lowercased name is fixed across runs and Rust versions (unlike std's
+/// hasher), so a repository keeps landing in the same directory.
+fn destination_index(name: &str, destinations: usize) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in name.to_lowercase().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash % destinations as u64) as usize
+}
+
+/// Sorts by `pushed_at` descending so an interrupted run has already captured
+/// the freshest repositories. Timestamps are RFC 3339 in UTC, so they compare
+/// correctly as strings; repositories never pushed to go last.
+fn order_by_recent_push(repos: &mut [RepoInfo]) {
+    repos.sort_by(|a, b| b.pushed_at.cmp(&a.pushed_at));
+}
+
+/// Drops the repositories ordered before `name` (matched case-insensitively
+/// against the name or `owner/name`) and returns how many were dropped.
+fn skip_until(repos: &mut Vec<RepoInfo>, name: &str) -> Result<usize, String> {
+    let position = repos
+        .iter()
+        .position(|repo| {
+            repo.name.eq_ignore_ascii_case(name) || repo.full_name.eq_ignore_ascii_case(name)
+        })
+        .ok_or_else(|| {
+            format!(
+                "--resume-from: {} is not among the {} repositories matching the filters",
+                name,
+                repos.len()
+            )
+        })?;
+    repos.drain(..position);
+    Ok(position)
+}
+
+/// `--parallel-ramp`: adds the permits beyond the first one at a time,
+/// evenly spaced, so `parallel_count` downloads are allowed once `over` has
+/// passed. Opening with one connection instead of a burst keeps GitHub's
+/// abuse detection from answering the first requests with 429s.
+async fn ramp_permits(semaphore: Arc<Semaphore>, parallel_count: usize, over: Duration) {
+    let Some(extra) = parallel_count.checked_sub(1).filter(|extra| *extra > 0) else {
+        return;
+    };
+    let step = over / extra as u32;
+    for _ in 0..extra {
+        tokio::time::sleep(step).await;
+        semaphore.add_permits(1);
+    }
+}
+
+/// Drops repositories whose `--watch` backoff has not expired and returns
+/// how many were dropped.
+fn defer_failing(repos: &mut Vec<RepoInfo>, retry_at: &HashMap<String, Instant>) -> usize {
+    let now = Instant::now();
+    let before = repos.len();
+    repos.retain(|repo| retry_at.get(&repo.full_name).is_none_or(|at| *at <= now));
+    before - repos.len()
+}
+
+fn filter_repos(repos: Vec<RepoInfo>, filters: &Filters) -> Vec<RepoInfo> {
+    repos
+        .into_iter()
+        .filter(|repo| filters.exclusion_reason(repo).is_none())
+        .collect()
+}
+
+fn extract_zip(
+    zip_path: &str,
+    repo_path: &Path,
+    options: &ExtractOptions,
+    log: &RepoLog,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let file = fs::File::open(zip_path)?;
+    let mut archive = ZipArchive::new(file)?;
+    let mut extraction = Extraction::new(repo_path, options, log);
+
+    // GitHub stores the archived commit SHA as the zip comment.
+    if let Ok(comment) = std::str::from_utf8(archive.comment())
+        && !comment.is_empty()
+    {
+        log.event(format!("archive commit {}", comment.trim()));
+    }
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        let entry = ArchiveEntry {
+            path: file.enclosed_name().map(Path::to_path_buf),
+            is_dir: name.ends_with('/'),
+            mode: file.unix_mode(),
+            size: file.size(),
+            name,
+        };
+        // Reading to the end checks the entry's CRC.
+        extraction.entry(entry, &mut file)?;
+    }
+
+    extraction.finish()
+}
+
+/// Extracts a gzipped tarball while it is read from `reader`, with the same
+/// path checks and options as `extract_zip`. Returns the files skipped and
+/// the commit GitHub records as the tarball's pax global comment.
+fn extract_tar<R: Read>(
+    reader: R,
+    repo_path: &Path,
+    options: &ExtractOptions,
+    log: &RepoLog,
+) -> Result<(u64, Option<String>), Box<dyn std::error::Error>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+    let mut extraction = Extraction::new(repo_path, options, log);
+    let mut commit = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() {
+            // GitHub stores the archived commit SHA as the global comment;
+            // a header that does not parse only loses the commit.
+            let extensions = entry.pax_extensions().ok().flatten();
+            for extension in extensions.into_iter().flatten().flatten() {
+                if extension.key() == Ok("comment") {
+                    commit = extension
+                        .value()
+                        .ok()
+                        .map(str::trim)
+                        .filter(|sha| sha.len() == 40 && sha.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .map(str::to_string);
+                }
+            }
+            if let Some(sha) = &commit {
+                log.event(format!("archive commit {}", sha));
+            }
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let path = entry.path()?;
+        let mut archive_entry = ArchiveEntry {
+            path: enclosed_path(&path),
+            is_dir: kind.is_dir(),
+            mode: tar_unix_mode(kind, entry.header().mode().ok()),
+            size: entry.size(),
+            name,
+        };
+        // A zip stores a symlink's target as its contents; a tar header
+        // holds it instead, so hand it over the same way.
+        if kind.is_symlink() {
+            let target = entry
+                .link_name()?
+                .map(|target| target.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            archive_entry.size = target.len() as u64;
+            extraction.entry(archive_entry, &mut target.as_bytes())?;
+        } else {
+            extraction.entry(archive_entry, &mut entry)?;
+        }
+    }
+
+    Ok((extraction.finish()?, commit))
+}
+
+/// `path` if it stays inside the extraction directory: relative, with no
+/// `..` components. Like `enclosed_name` for zip entries.
+fn enclosed_path(path: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+
+    let mut enclosed = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => enclosed.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(enclosed)
+}
+
+/// A tar entry's Unix mode with the file type bits, which tar keeps in
+/// the header's entry type rather than the mode, as `overlay_unsafe_entry`
+/// expects them.
+fn tar_unix_mode(kind: tar::EntryType, permissions: Option<u32>) -> Option<u32> {
+    let file_type = match kind {
+        tar::EntryType::Regular | tar::EntryType::Continuous => 0o100000,
+        tar::EntryType::Directory => 0o040000,
+        tar::EntryType::Symlink => 0o120000,
+        tar::EntryType::Char => 0o020000,
+        tar::EntryType::Block => 0o060000,
+        tar::EntryType::Fifo => 0o010000,
+        _ => return None,
+    };
+    Some(file_type | permissions.unwrap_or(0) & 0o7777)
+}
+
+/// One archive entry, as `extract_zip` and `extract_tar` describe it to
+/// `Extraction::entry`.
+struct ArchiveEntry {
+    /// Name as stored in the archive, for log lines.
+    name: String,
+    /// `None` when the path would leave the extraction directory.
+    path: Option<PathBuf>,
+    is_dir: bool,
+    mode: Option<u32>,
+    size: u64,
+}
+
+/// Extraction of one archive into `repo_path`, shared by the zip and tar
+/// readers so both apply the same filters.
+struct Extraction<'a> {
+    repo_path: &'a Path,
+    options: &'a ExtractOptions,
+    log: &'a RepoLog,
+    written: u64,
+    skipped: u64,
+    // `--resolve-symlinks` links and their targets, copied once every
+    // regular file is out.
+    symlinks: Vec<(PathBuf, String)>,
+}
+
+impl<'a> Extraction<'a> {
+    fn new(repo_path: &'a Path, options: &'a ExtractOptions, log: &'a RepoLog) -> Self {
+        Self {
+            repo_path,
+            options,
+            log,
+            written: 0,
+            skipped: 0,
+            symlinks: Vec::new(),
+        }
+    }
+
+    fn entry(
+        &mut self,
+        entry: ArchiveEntry,
+        contents: &mut dyn Read,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let options = self.options;
+        let outpath = match entry.path {
+            Some(path) => path,
+            None if options.verify => {
+                return Err(format!("unsafe path {}", entry.name).into());
+            }
+            None => {
+                self.log
+                    .event(format!("skipped unsafe path {}", entry.name));
+                return Ok(());
+            }
+        };
+
+        let outpath = match options.strip(&outpath) {
+            Some(path) => self.repo_path.join(path),
+            None => return Ok(()),
+        };
+
+        if options.overlay_safe {
+            match overlay_unsafe_entry(entry.mode, &outpath) {
+                Some(EntryKind::Symlink) if options.resolve_symlinks && options.wants(&outpath) => {
+                    let mut target = String::new();
+                    contents.read_to_string(&mut target)?;
+                    self.symlinks.push((outpath, target));
+                    return Ok(());
+                }
+                Some(kind) => {
+                    self.log
+                        .event(format!("skipped {} ({})", entry.name, kind.describe()));
+                    self.skipped += 1;
+                    return Ok(());
+                }
+                None => {}
+            }
+        }
+
+        if entry.is_dir {
+            fs::create_dir_all(&outpath)?;
+        } else if !options.wants(&outpath) {
+            self.log
+                .event(format!("skipped {} (extension not allowed)", entry.name));
+            self.skipped += 1;
+            if options.empty_dirs == EmptyDirs::Keep
+                && let Some(p) = outpath.parent()
+            {
+                fs::create_dir_all(p)?;
+            }
+        } else {
+            if let Some(p) = outpath.parent() {
+                fs::create_dir_all(p)?;
+            }
+            let mut outfile = fs::File::create(&outpath)?;
+            let (len, hash) = copy_hashed(contents, &mut outfile)?;
+            drop(outfile);
+            if options.verify && len != entry.size {
+                return Err(
+                    format!("{} extracted {} of {} bytes", entry.name, len, entry.size).into(),
+                );
+            }
+
+            self.written += 1;
+
+            if let Some(index) = &options.dedupe {
+                index.link_or_insert(hash, len, &outpath);
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `--resolve-symlinks` targets, prunes and verifies, and
+    /// returns the number of files skipped.
+    fn finish(mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        let repo_path = self.repo_path;
+        let options = self.options;
+        let log = self.log;
+
+        if !self.symlinks.is_empty() {
+            let (copied, unresolved) = resolve_symlinks(repo_path, self.symlinks)?;
+            self.written += copied;
+            for (link, target) in &unresolved {
+                log.event(format!(
+                    "skipped symlink {} -> {} (not a file in the repository)",
+                    link.display(),
+                    target
+                ));
+            }
+            self.skipped += unresolved.len() as u64;
+        }
+
+        if options.empty_dirs == EmptyDirs::Prune {
+            let pruned = prune_empty_dirs(repo_path)?;
+            if pruned > 0 {
+                log.event(format!("pruned {} empty directories", pruned));
+            }
+        }
+
+        // Entries whose paths collide, e.g. on a case-insensitive filesystem,
+        // overwrite each other and leave fewer files than were written.
+        if options.verify {
+            let found = count_files(repo_path)?;
+            if found != self.written {
+                return Err(
+                    format!("extracted {} files but {} are on disk", self.written, found).into(),
+                );
+            }
+        }
+
+        log.event(format!(
+            "extracted {} files, skipped {}",
+            self.written, self.skipped
+        ));
+        Ok(self.skipped)
+    }
+}
+
+/// Archive entries that `--overlay-safe` keeps off the disk.
+#[derive(Clone, Copy, PartialEq)]
+enum EntryKind {
+    Symlink,
+    /// Device, fifo or socket.
+    Special,
+    /// An OCI/AUFS whiteout name, which would delete files from lower
+    /// layers once the tree is used as an image layer.
+    Whiteout,
+}
+
+impl EntryKind {
+    fn describe(self) -> &'static str {
+        match self {
+            EntryKind::Symlink => "symlink",
+            EntryKind::Special => "special file",
+            EntryKind::Whiteout => "overlay whiteout name",
+        }
+    }
+}
+
+/// Classifies an entry by the file type bits of its Unix mode, when the
+/// archive recorded one, and by name; `None` for regular files and
+/// directories.
+fn overlay_unsafe_entry(mode: Option<u32>, path: &Path) -> Option<EntryKind> {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFREG: u32 = 0o100000;
+    const S_IFDIR: u32 = 0o040000;
+    const S_IFLNK: u32 = 0o120000;
+
+    if path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(".wh."))
+    {
+        return Some(EntryKind::Whiteout);
+    }
+    match mode.map(|mode| mode & S_IFMT) {
+        None | Some(0) | Some(S_IFREG) | Some(S_IFDIR) => None,
+        Some(S_IFLNK) => Some(EntryKind::Symlink),
+        Some(_) => Some(EntryKind::Special),
+    }
+}
+
+/// Replaces each link with a copy of its target, resolved lexically from
+/// the link's directory. Targets outside `repo_path`, directories and
+/// missing files are left unresolved; links to other links resolve once
+/// the link they point to has been copied. Returns the number of copies
+/// and the links that could not be resolved.
+fn resolve_symlinks(
+    repo_path: &Path,
+    mut links: Vec<(PathBuf, String)>,
+) -> std::io::Result<(u64, Vec<(PathBuf, String)>)> {
+    let mut copied = 0;
+    loop {
+        let before = links.len();
+        let mut pending = Vec::new();
+        for (link, target) in links {
+            let resolved = link
+                .parent()
+                .and_then(|dir| normalize_within(repo_path, &dir.join(&target)));
+            match resolved {
+                Some(source) if source.is_file() && !source.is_symlink() => {
+                    if let Some(parent) = link.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::copy(&source, &link)?;
+                    copied += 1;
+                }
+                _ => pending.push((link, target)),
+            }
+        }
+        links = pending;
+        if links.is_empty() || links.len() == before {
+            return Ok((copied, links));
+        }
+    }
+}
+
+/// `path` with `.` and `..` components folded away, or `None` if it
+/// leaves `root`.
+fn normalize_within(root: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut normalized = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            std::path::Component::Normal(part) => normalized.push(part),
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return None,
+        }
+    }
+    Some(root.join(normalized))
+}
+
+fn count_files(dir: &Path) -> std::io::Result<u64> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_file() {
+            count += 1;
+        } else if file_type.is_dir() {
+            count += count_files(&entry.path())?;
+        }
+    }
+    Ok(count)
+}
+
+/// Scratch directory for `--trial-run-extract`: under `/dev/shm` when it
+/// exists, so trial extractions stay in memory, otherwise the system
+/// temporary directory.
+fn trial_extract_root() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    let parent = if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    };
+    parent.join(format!("magnet-trial-{}", std::process::id()))
+}
+
+/// Whether extraction failed with `ENOSPC`, directly or inside a zip error.
+fn is_storage_full(e: &(dyn std::error::Error + 'static)) -> bool {
+    let io = match e.downcast_ref::<zip::result::ZipError>() {
+        Some(zip::result::ZipError::Io(io)) => io,
+        Some(_) => return false,
+        None => match e.downcast_ref::<std::io::Error>() {
+            Some(io) => io,
+            None => return false,
+        },
+    };
+    io.kind() == std::io::ErrorKind::StorageFull
+}
+
+/// Free space left when `ENOSPC` still counts as "out of inodes": enough
+/// for any single file the archive was likely to be writing.
+const INODE_EXHAUSTION_MIN_FREE: u64 = 16 * 1024 * 1024;
+
+/// Explains an `ENOSPC` during extraction. The errno is the same whether
+/// bytes or inodes ran out, so plenty of free bytes means it was inodes:
+/// archives of many tiny files exhaust small or network filesystems first.
+fn storage_full_message(repo_path: &Path) -> String {
+    let dir = repo_path.parent().unwrap_or(Path::new("."));
+    match fs2::available_space(dir) {
+        Ok(free) if free >= INODE_EXHAUSTION_MIN_FREE => format!(
+            "out of inodes: {} has {} free but no room for more files \
+             (--compact-small-repos keeps small repositories as archives)",
+            dir.display(),
+            format_size(free)
+        ),
+        _ => format!("out of disk space in {}", dir.display()),
+    }
+}
+
+/// Removes empty directories below `dir`, deepest first, so a directory
+/// holding only empty ones goes too. `dir` itself is kept.
+fn prune_empty_dirs(dir: &Path) -> std::io::Result<usize> {
+    let mut pruned = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            pruned += prune_empty_dirs(&path)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+                pruned += 1;
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+/// Timestamped per-repository event list behind `--per-repo-log`. Events
+/// are always collected; the file is only written when the flag is set.
+struct RepoLog {
+    /// `owner/name` the events belong to.
+    repo: String,
+    /// The repository's `retry_budget`.
+    attempts: u32,
+    started: Instant,
+    lines: std::sync::Mutex<Vec<String>>,
+}
+
+impl RepoLog {
+    fn new(repo: &str, attempts: u32) -> Self {
+        Self {
+            repo: repo.to_string(),
+            attempts,
+            started: Instant::now(),
+            lines: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn event(&self, message: impl AsRef<str>) {
+        let line = format!(
+            "[{:>8.3}s] {}",
+            self.started.elapsed().as_secs_f64(),
+            message.as_ref()
+        );
+        self.lines.lock().unwrap().push(line);
+    }
+
+    fn retry(&self, attempt: u32, reason: &str, delay: Option<Duration>) {
+        match delay {
+            Some(delay) => self.event(format!(
+                "attempt {} failed ({}), retrying in {} ms",
+                attempt + 1,
+                reason,
+                delay.as_millis()
+            )),
+            None => self.event(format!(
+                "attempt {} failed ({}), giving up",
+                attempt + 1,
+                reason
+            )),
+        }
+    }
+
+    /// Appends this run's events, so re-runs extend the history.
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let started_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        writeln!(file, "=== magnet run at unix time {} ===", started_at)?;
+        for line in self.lines.lock().unwrap().iter() {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn copy_hashed<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> std::io::Result<(u64, [u8; 32])> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 16 * 1024];
+    let mut len = 0;
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        len += n as u64;
+    }
+
+    Ok((len, hasher.finalize().into()))
+}
+
+/// Content index used by `--dedupe`: the first copy of each file becomes the
+/// canonical one and later byte-identical copies are replaced by hardlinks.
+struct DedupeIndex {
+    files: std::sync::Mutex<HashMap<[u8; 32], (u64, PathBuf)>>,
+    bytes_saved: AtomicU64,
+    files_linked: AtomicU64,
+}
+
+impl DedupeIndex {
+    fn new() -> Self {
+        Self {
+            files: std::sync::Mutex::new(HashMap::new()),
+            bytes_saved: AtomicU64::new(0),
+            files_linked: AtomicU64::new(0),
+        }
+    }
+
+    fn link_or_insert(&self, hash: [u8; 32], len: u64, path: &Path) {
+        if len == 0 {
+            return;
+        }
+
+        let mut files = self.files.lock().unwrap();
+        let canonical = match files.get(&hash) {
+            Some((canonical_len, canonical)) if *canonical_len == len => canonical.clone(),
+            _ => {
+                files.insert(hash, (len, path.to_path_buf()));
+                return;
+            }
+        };
+
+        // Only link when both paths live on the same filesystem, and do it via
+        // a temporary link + rename so a failure never loses the new copy.
+        if !same_device(&canonical, path) {
+            return;
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".magnet-link");
+        let tmp = PathBuf::from(tmp);
+        if fs::hard_link(&canonical, &tmp).is_err() {
+            // The canonical copy may have vanished or hit a link limit; let
+            // the new copy take its place for future matches.
+            files.insert(hash, (len, path.to_path_buf()));
+            return;
+        }
+
+        if fs::rename(&tmp, path).is_ok() {
+            self.bytes_saved.fetch_add(len, Ordering::Relaxed);
+            self.files_linked.fetch_add(1, Ordering::Relaxed);
+        } else {
+            fs::remove_file(&tmp).ok();
+        }
+    }
+
+    fn bytes_saved(&self) -> u64 {
+        self.bytes_saved.load(Ordering::Relaxed)
+    }
+
+    fn files_linked(&self) -> u64 {
+        self.files_linked.load(Ordering::Relaxed)
+    }
+}
+
+/// Archive index used by `--dedupe-archives`: a repository whose archive is
+/// byte-identical to one already extracted this run, typically an unchanged
+/// fork that kept its name, gets a tree of hardlinks to that extraction
+/// instead of being decompressed again.
+struct ArchiveIndex {
+    /// Archive hash to the extracted directory and its repository.
+    extracted: std::sync::Mutex<HashMap<[u8; 32], (PathBuf, String)>>,
+    /// Directories filled by linking, to the repository they duplicate.
+    linked: std::sync::Mutex<HashMap<PathBuf, String>>,
+}
+
+impl ArchiveIndex {
+    fn new() -> Self {
+        Self {
+            extracted: std::sync::Mutex::new(HashMap::new()),
+            linked: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fills `repo_path` with hardlinks to an earlier extraction of the
+    /// same archive. Returns false, leaving nothing behind, when there is
+    /// none or it cannot be linked (another filesystem, or a file of it was
+    /// removed), and the caller extracts as usual.
+    fn link(&self, hash: [u8; 32], repo_path: &Path, log: &RepoLog) -> bool {
+        let Some((canonical, repo)) = self.extracted.lock().unwrap().get(&hash).cloned() else {
+            return false;
+        };
+        if !repo_path
+            .parent()
+            .is_some_and(|parent| same_device(&canonical, parent))
+        {
+            return false;
+        }
+
+        match link_tree(&canonical, repo_path) {
+            Ok(files) => {
+                log.event(format!(
+                    "archive identical to {}, linked {} files",
+                    repo, files
+                ));
+                self.linked
+                    .lock()
+                    .unwrap()
+                    .insert(repo_path.to_path_buf(), repo);
+                true
+            }
+            Err(e) => {
+                log.event(format!("could not link to {} ({}), extracting", repo, e));
+                fs::remove_dir_all(repo_path).ok();
+                false
+            }
+        }
+    }
+
+    /// Records a completed extraction; the first one of each archive stays
+    /// the one later duplicates link to.
+    fn insert(&self, hash: [u8; 32], repo_path: &Path, repo: &str) {
+        self.extracted
+            .lock()
+            .unwrap()
+            .entry(hash)
+            .or_insert_with(|| (repo_path.to_path_buf(), repo.to_string()));
+    }
+
+    fn duplicate_of(&self, repo_path: &Path) -> Option<String> {
+        self.linked.lock().unwrap().get(repo_path).cloned()
+    }
+}
+
+fn archive_hash(zip_path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut file = fs::File::open(zip_path)?;
+    let (_, hash) = copy_hashed(&mut file, &mut std::io::sink())?;
+    Ok(hash)
+}
+
+/// Recreates `from`'s directories under `to` and hardlinks its files,
+/// except a per-repository log, which belongs to `from`'s repository.
+/// Returns the number of files linked.
+fn link_tree(from: &Path, to: &Path) -> std::io::Result<u64> {
+    fs::create_dir_all(to)?;
+    let mut linked = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            linked += link_tree(&entry.path(), &target)?;
+        } else if file_type.is_file() && entry.file_name() != REPO_LOG_FILE {
+            fs::hard_link(entry.path(), &target)?;
+            linked += 1;
+        }
+    }
+    Ok(linked)
+}
+
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Extension table for `--language-detect`. Only source extensions are
+/// listed; docs, data and config files don't vote.
+const LANGUAGE_EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "Rust"),
+    ("go", "Go"),
+    ("py", "Python"),
+    ("ipynb", "Jupyter Notebook"),
+    ("js", "JavaScript"),
+    ("jsx", "JavaScript"),
+    ("mjs", "JavaScript"),
+    ("ts", "TypeScript"),
+    ("tsx", "TypeScript"),
+    ("java", "Java"),
+    ("kt", "Kotlin"),
+    ("scala", "Scala"),
+    ("c", "C"),
+    ("h", "C"),
+    ("cc", "C++"),
+    ("cpp", "C++"),
+    ("cxx", "C++"),
+    ("hpp", "C++"),
+    ("cs", "C#"),
+    ("rb", "Ruby"),
+    ("php", "PHP"),
+    ("swift", "Swift"),
+    ("m", "Objective-C"),
+    ("sh", "Shell"),
+    ("bash", "Shell"),
+    ("lua", "Lua"),
+    ("pl", "Perl"),
+    ("r", "R"),
+    ("dart", "Dart"),
+    ("ex", "Elixir"),
+    ("exs", "Elixir"),
+    ("erl", "Erlang"),
+    ("hs", "Haskell"),
+    ("ml", "OCaml"),
+    ("clj", "Clojure"),
+    ("zig", "Zig"),
+    ("nim", "Nim"),
+    ("jl", "Julia"),
+    ("vue", "Vue"),
+    ("html", "HTML"),
+    ("css", "CSS"),
+    ("scss", "SCSS"),
+];
+
+/// Picks the language with the most bytes of source in `dir`, by extension.
+fn detect_language(dir: &Path) -> Option<String> {
+    let mut totals: HashMap<&'static str, u64> = HashMap::new();
+    tally_languages(dir, &mut totals);
+
+    totals
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+        .map(|(language, _)| language.to_string())
+}
+
+fn tally_languages(dir: &Path, totals: &mut HashMap<&'static str, u64>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            if entry.file_name() != ".git" {
+                tally_languages(&path, totals);
+            }
+            continue;
+        }
+
+        let ext = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext.to_lowercase(),
+            None => continue,
+        };
+        if let Some((_, language)) = LANGUAGE_EXTENSIONS.iter().find(|(e, _)| *e == ext) {
+            *totals.entry(language).or_insert(0) += metadata.len();
+        }
+    }
+}
+
+fn get_dir_size(dir: &Path) -> Result<u64, std::io::Error> {
+    dir_stats(dir).map(|(size, _)| size)
+}
+
+/// Bytes under `dir` and its tree depth: the most path components below
+/// `dir` of any entry, so a file at the top is 1 deep and `a/b/c.txt` 3.
+fn dir_stats(dir: &Path) -> Result<(u64, u32), std::io::Error> {
+    let mut size = 0;
+    let mut depth = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if metadata.is_file() {
+            size += metadata.len();
+            depth = depth.max(1);
+        } else if metadata.is_dir() {
+            let (subdir_size, subdir_depth) = dir_stats(&entry.path()).unwrap_or((0, 0));
+            size += subdir_size;
+            depth = depth.max(subdir_depth + 1);
+        }
+    }
+    Ok((size, depth))
 }
 
-struct ProgressTracker {
-    total: usize,
-    completed: Mutex<usize>,
-    downloaded: Mutex<usize>,
-    failed: Mutex<usize>,
-    total_size: Mutex<u64>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
 
-impl ProgressTracker {
-    fn new(total: usize) -> Self {
-        Self {
-            total,
-            completed: Mutex::new(0),
-            downloaded: Mutex::new(0),
-            failed: Mutex::new(0),
-            total_size: Mutex::new(0),
-        }
+    /// One canned answer of `ScriptedTransport`.
+    #[derive(Clone)]
+    enum Reply {
+        Response {
+            status: u16,
+            headers: Vec<(&'static str, String)>,
+            body: Vec<u8>,
+        },
+        ConnectionError,
     }
 
-    async fn report_completion(&self, name: String, result: Result<u64, String>) {
-        let mut completed = self.completed.lock().await;
-        *completed += 1;
-        let current = *completed;
+    impl Reply {
+        fn status(status: u16) -> Self {
+            Reply::Response {
+                status,
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
 
-        match result {
-            Ok(size) => {
-                let mut downloaded = self.downloaded.lock().await;
-                let mut total_size = self.total_size.lock().await;
-                *downloaded += 1;
-                *total_size += size;
-                println!("[{}/{}] {} ({} KB)", current, self.total, name, size / 1024);
+        fn json(value: serde_json::Value) -> Self {
+            Reply::Response {
+                status: 200,
+                headers: Vec::new(),
+                body: value.to_string().into_bytes(),
             }
-            Err(e) => {
-                let mut failed = self.failed.lock().await;
-                *failed += 1;
-                println!("[{}/{}] {} FAILED: {}", current, self.total, name, e);
+        }
+
+        fn bytes(body: Vec<u8>) -> Self {
+            Reply::Response {
+                status: 200,
+                headers: Vec::new(),
+                body,
             }
         }
-    }
 
-    async fn get_stats(&self) -> Stats {
-        Stats {
-            downloaded: *self.downloaded.lock().await,
-            failed: *self.failed.lock().await,
-            total_size: *self.total_size.lock().await,
+        fn header(mut self, name: &'static str, value: &str) -> Self {
+            if let Reply::Response { headers, .. } = &mut self {
+                headers.push((name, value.to_string()));
+            }
+            self
         }
     }
-}
 
-#[derive(Debug, Clone, Deserialize)]
-struct RepoInfo {
-    name: String,
-    html_url: String,
-    language: Option<String>,
-    #[serde(rename = "stargazers_count")]
-    stars: u32,
-    size: u32,
-    #[serde(rename = "fork")]
-    is_fork: bool,
-    default_branch: String,
-}
+    /// Answers requests by URL path from scripted queues; the last reply of
+    /// a queue repeats, and unscripted paths get a 404. Every request path is
+    /// recorded in order.
+    #[derive(Default)]
+    struct ScriptedTransport {
+        routes: std::sync::Mutex<HashMap<String, VecDeque<Reply>>>,
+        requests: std::sync::Mutex<Vec<String>>,
+    }
 
-#[derive(Deserialize)]
-struct RateLimitResponse {
-    rate: RateLimit,
-}
+    impl ScriptedTransport {
+        fn on(self, path: &str, replies: impl IntoIterator<Item = Reply>) -> Self {
+            self.routes
+                .lock()
+                .unwrap()
+                .insert(path.to_string(), replies.into_iter().collect());
+            self
+        }
 
-#[derive(Deserialize)]
-struct RateLimit {
-    remaining: u32,
-}
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
 
-struct Scraper {
-    client: Client,
-    token: Option<String>,
-}
+        fn count(&self, path: &str) -> usize {
+            self.requests().iter().filter(|p| *p == path).count()
+        }
 
-impl Scraper {
-    fn new(token: Option<String>) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert("Accept", "application/vnd.github.v3+json".parse().unwrap());
-        headers.insert("User-Agent", "magnet/2.0".parse().unwrap());
+        fn next_reply(&self, path: &str) -> Reply {
+            let mut routes = self.routes.lock().unwrap();
+            match routes.get_mut(path) {
+                Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+                Some(queue) => queue.front().cloned().unwrap_or(Reply::status(404)),
+                None => Reply::status(404),
+            }
+        }
+    }
 
-        if let Some(ref token) = token {
-            headers.insert(
-                "Authorization",
-                format!("Bearer {}", token).parse().unwrap(),
-            );
+    impl Transport for ScriptedTransport {
+        fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+            let path = request.url().path().to_string();
+            self.requests.lock().unwrap().push(path.clone());
+            let reply = self.next_reply(&path);
+            Box::pin(async move {
+                match reply {
+                    Reply::Response {
+                        status,
+                        headers,
+                        body,
+                    } => {
+                        let mut response = http::Response::builder().status(status);
+                        for (name, value) in headers {
+                            response = response.header(name, value);
+                        }
+                        Ok(reqwest::Response::from(response.body(body).unwrap()))
+                    }
+                    // Nothing listens on port 1, so this is a genuine
+                    // connection error.
+                    Reply::ConnectionError => Client::new().get("http://127.0.0.1:1/").send().await,
+                }
+            })
         }
+    }
 
-        let client = Client::builder()
-            .timeout(Duration::from_secs(300))
-            .default_headers(headers)
-            .build()?;
+    fn scraper(transport: &Arc<ScriptedTransport>) -> Scraper {
+        scraper_with(transport, Config::default())
+    }
 
-        Ok(Self { client, token })
+    fn scraper_with(transport: &Arc<ScriptedTransport>, config: Config) -> Scraper {
+        Scraper::new(Config {
+            transport: Some(transport.clone()),
+            ..config
+        })
+        .unwrap()
     }
 
-    async fn fetch_all_repos(&self, username: &str) -> Result<Vec<RepoInfo>, String> {
-        let mut repos = Vec::new();
-        let mut page = 1;
+    async fn get(scraper: &Scraper, path: &str) -> Result<reqwest::Response, String> {
+        let url = format!("{}{}", GITHUB_API_BASE, path);
+        scraper
+            .retry_request(None, || scraper.client.get(&url))
+            .await
+    }
 
-        loop {
-            let url = format!(
-                "{}/users/{}/repos?per_page=100&page={}",
-                GITHUB_API_BASE, username, page
-            );
+    #[tokio::test(start_paused = true)]
+    async fn retries_rate_limited_responses_until_success() {
+        let transport = Arc::new(ScriptedTransport::default().on(
+            "/x",
+            [Reply::status(429), Reply::status(429), Reply::status(200)],
+        ));
+        let scraper = scraper(&transport);
 
-            let response = self.retry_request(|| self.client.get(&url).send()).await?;
+        let response = get(&scraper, "/x").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(transport.count("/x"), 3);
+    }
 
-            if !response.status().is_success() {
-                return Err(format!("GitHub API error: {}", response.status()));
-            }
+    #[tokio::test(start_paused = true)]
+    async fn retries_connection_errors() {
+        let transport = Arc::new(
+            ScriptedTransport::default()
+                .on("/x", [Reply::ConnectionError, Reply::status(200)])
+                .on("/down", [Reply::ConnectionError]),
+        );
+        let scraper = scraper(&transport);
 
-            let data: Vec<RepoInfo> = response.json().await.map_err(|e| e.to_string())?;
+        assert_eq!(get(&scraper, "/x").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(transport.count("/x"), 2);
 
-            if data.is_empty() {
-                break;
-            }
+        assert!(get(&scraper, "/down").await.is_err());
+        assert_eq!(transport.count("/down"), MAX_RETRIES as usize);
+    }
 
-            repos.extend(data);
-            page += 1;
-        }
+    #[tokio::test(start_paused = true)]
+    async fn does_not_retry_other_errors() {
+        let transport = Arc::new(ScriptedTransport::default().on("/x", [Reply::status(451)]));
+        let scraper = scraper(&transport);
 
-        if self.token.is_none() {
-            self.check_rate_limit().await.ok();
-        }
+        let response = get(&scraper, "/x").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        assert_eq!(transport.count("/x"), 1);
+    }
 
-        Ok(repos)
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_cuts_a_backoff_short() {
+        let transport = Arc::new(ScriptedTransport::default().on("/x", [Reply::status(429)]));
+        let scraper = Arc::new(scraper(&transport));
+        let canceller = {
+            let scraper = scraper.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                scraper.cancel.cancel("interrupted".to_string());
+            })
+        };
+
+        let started = tokio::time::Instant::now();
+        let error = get(&scraper, "/x").await.unwrap_err();
+        canceller.await.unwrap();
+
+        assert_eq!(error, "cancelled: interrupted");
+        assert!(started.elapsed() < Duration::from_millis(RETRY_DELAY_MS));
+        assert_eq!(transport.count("/x"), 1);
     }
 
-    async fn check_rate_limit(&self) -> Result<(), String> {
-        let url = format!("{}/rate_limit", GITHUB_API_BASE);
-        let response = self
-            .client
-            .get(&url)
-            .send()
+    /// A fresh directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("magnet-test-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const ARCHIVE_PATH: &str = "/bob/alpha/archive/refs/heads/main.zip";
+
+    async fn fetch(scraper: &Scraper, name: &str) -> Result<PathBuf, DownloadError> {
+        let url = format!("https://github.com{}", ARCHIVE_PATH);
+        let repo_path = temp_dir(name).join("alpha");
+        scraper
+            .fetch_archive(&url, &repo_path, &RepoLog::new("bob/alpha", MAX_RETRIES))
             .await
-            .map_err(|e| e.to_string())?;
+    }
 
-        if response.status().is_success() {
-            let data: RateLimitResponse = response.json().await.map_err(|e| e.to_string())?;
-            if data.rate.remaining < 10 {
-                eprintln!(
-                    "WARNING: GitHub API rate limit low: {} remaining",
-                    data.rate.remaining
-                );
-            }
-        }
+    #[tokio::test(start_paused = true)]
+    async fn polls_an_archive_until_it_is_generated() {
+        let transport = Arc::new(ScriptedTransport::default().on(
+            ARCHIVE_PATH,
+            [
+                Reply::status(202),
+                Reply::status(202),
+                Reply::bytes(b"archive".to_vec()),
+            ],
+        ));
+        let scraper = scraper_with(
+            &transport,
+            Config {
+                archive_generation_timeout: Some(Duration::from_secs(60)),
+                ..Config::default()
+            },
+        );
 
-        Ok(())
+        let started = tokio::time::Instant::now();
+        let part = fetch(&scraper, "generated").await.unwrap();
+        assert_eq!(fs::read(part).unwrap(), b"archive");
+        assert_eq!(transport.count(ARCHIVE_PATH), 3);
+        // The default polling delay doubles: 1s, then 2s.
+        assert_eq!(started.elapsed().as_secs(), 3);
     }
 
-    async fn retry_request<F, Fut>(&self, mut request_fn: F) -> Result<reqwest::Response, String>
-    where
-        F: FnMut() -> Fut,
-        Fut: std::future::Future<Output = Result<reqwest::Response, reqwest::Error>>,
-    {
-        let mut last_error = None;
+    #[tokio::test(start_paused = true)]
+    async fn polls_archives_as_retry_after_asks() {
+        let transport = Arc::new(ScriptedTransport::default().on(
+            ARCHIVE_PATH,
+            [
+                Reply::status(202).header("retry-after", "5"),
+                Reply::bytes(b"archive".to_vec()),
+            ],
+        ));
+        let scraper = scraper_with(
+            &transport,
+            Config {
+                archive_generation_timeout: Some(Duration::from_secs(60)),
+                ..Config::default()
+            },
+        );
 
-        for attempt in 0..MAX_RETRIES {
-            match request_fn().await {
-                Ok(response) => {
-                    if response.status().is_success() || response.status() == StatusCode::NOT_FOUND
-                    {
-                        return Ok(response);
-                    }
+        let started = tokio::time::Instant::now();
+        fetch(&scraper, "retry-after").await.unwrap();
+        assert_eq!(started.elapsed().as_secs(), 5);
+    }
 
-                    if response.status() == StatusCode::FORBIDDEN
-                        || response.status() == StatusCode::TOO_MANY_REQUESTS
-                    {
-                        tokio::time::sleep(Duration::from_millis(
-                            RETRY_DELAY_MS * 2_u64.pow(attempt),
-                        ))
-                        .await;
-                        continue;
-                    }
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_archives_still_generating_after_the_timeout() {
+        let transport =
+            Arc::new(ScriptedTransport::default().on(ARCHIVE_PATH, [Reply::status(202)]));
+        let patient = scraper_with(
+            &transport,
+            Config {
+                archive_generation_timeout: Some(Duration::from_secs(10)),
+                ..Config::default()
+            },
+        );
 
-                    return Ok(response);
-                }
-                Err(e) => {
-                    last_error = Some(e);
-                    if attempt < MAX_RETRIES - 1 {
-                        tokio::time::sleep(Duration::from_millis(
-                            RETRY_DELAY_MS * 2_u64.pow(attempt),
-                        ))
-                        .await;
-                    }
+        let started = tokio::time::Instant::now();
+        let error = fetch(&patient, "timeout").await.unwrap_err();
+        assert!(
+            error.to_string().contains("still being generated"),
+            "{}",
+            error
+        );
+        assert_eq!(started.elapsed().as_secs(), 10);
+
+        // Without a timeout the first 202 fails.
+        let transport =
+            Arc::new(ScriptedTransport::default().on(ARCHIVE_PATH, [Reply::status(202)]));
+        let error = fetch(&scraper(&transport), "no-timeout").await.unwrap_err();
+        assert!(
+            error.to_string().contains("still being generated"),
+            "{}",
+            error
+        );
+        assert_eq!(transport.count(ARCHIVE_PATH), 1);
+    }
+
+    /// Relative paths of the files under `dir`, sorted.
+    fn files_under(dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let relative = path.strip_prefix(dir).unwrap();
+                    files.push(relative.to_string_lossy().replace('\\', "/"));
                 }
             }
         }
-
-        Err(last_error.unwrap().to_string())
+        files.sort();
+        files
     }
 
-    async fn download_repo(&self, repo: &RepoInfo, username: &str) -> Result<u64, String> {
-        let repo_path = Path::new(username).join(&repo.name);
-
-        if repo_path.exists() {
-            if let Ok(size) = get_dir_size(&repo_path) {
-                return Ok(size);
-            }
+    #[test]
+    fn strip_components_drops_leading_path_components() {
+        let dir = temp_dir("strip");
+        let zip_path = dir.join("alpha.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        let options = zip::write::FileOptions::default();
+        zip.add_directory("alpha-main/", options).unwrap();
+        for name in [
+            "alpha-main/README.md",
+            "alpha-main/src/lib.rs",
+            "alpha-main/src/deep/mod.rs",
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(b"x").unwrap();
         }
+        zip.finish().unwrap();
 
-        let branch = &repo.default_branch;
-        let zip_url = format!("{}/archive/refs/heads/{}.zip", repo.html_url, branch);
+        let log = RepoLog::new("bob/alpha", MAX_RETRIES);
+        let extracted = |strip_components: usize| {
+            let scraper = Scraper::new(Config {
+                strip_components,
+                ..Config::default()
+            })
+            .unwrap();
+            let repo_path = dir.join(format!("strip-{}", strip_components));
+            extract_zip(
+                &zip_path.to_string_lossy(),
+                &repo_path,
+                &scraper.extract,
+                &log,
+            )
+            .unwrap();
+            files_under(&repo_path)
+        };
 
-        match self.download_and_extract(&zip_url, &repo_path).await {
-            Ok(size) => Ok(size),
-            Err(e) => {
-                let fallback_branches = ["main", "master", "develop", "trunk"];
-                for fallback in &fallback_branches {
-                    if *fallback == branch {
-                        continue;
-                    }
+        assert_eq!(
+            extracted(0),
+            [
+                "alpha-main/README.md",
+                "alpha-main/src/deep/mod.rs",
+                "alpha-main/src/lib.rs"
+            ]
+        );
+        assert_eq!(extracted(1), ["README.md", "src/deep/mod.rs", "src/lib.rs"]);
+        // README.md has nothing left after two components and is skipped.
+        assert_eq!(extracted(2), ["deep/mod.rs", "lib.rs"]);
+    }
 
-                    let fallback_url =
-                        format!("{}/archive/refs/heads/{}.zip", repo.html_url, fallback);
+    /// A gzipped tarball laid out like GitHub's: a pax global header with
+    /// the commit, then everything under one wrapper directory.
+    fn tarball(commit: &str, entries: &[(&str, &str)]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
 
-                    if let Ok(size) = self.download_and_extract(&fallback_url, &repo_path).await {
-                        return Ok(size);
-                    }
-                }
+        // The record's length includes its own two digits.
+        let record = format!("52 comment={}\n", commit);
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        header.set_path("pax_global_header").unwrap();
+        header.set_size(record.len() as u64);
+        header.set_cksum();
+        builder.append(&header, record.as_bytes()).unwrap();
 
-                Err(format!("Failed to download: {}", e))
-            }
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_ustar();
+            // Written raw: `set_path` refuses the `..` an attacker would use.
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(if path.ends_with('/') {
+                tar::EntryType::Directory
+            } else {
+                tar::EntryType::Regular
+            });
+            header.set_mode(0o644);
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
         }
+        builder.into_inner().unwrap().finish().unwrap()
     }
 
-    async fn download_and_extract(&self, url: &str, repo_path: &Path) -> Result<u64, String> {
-        let response = self.retry_request(|| self.client.get(url).send()).await?;
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_tarballs_into_the_extractor() {
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        let body = tarball(
+            commit,
+            &[
+                ("bob-alpha-0123456/", ""),
+                ("bob-alpha-0123456/README.md", "hi"),
+                ("bob-alpha-0123456/src/lib.rs", "pub fn f() {}"),
+                ("bob-alpha-0123456/../escape.rs", "oops"),
+            ],
+        );
+        let transport = Arc::new(ScriptedTransport::default().on(
+            "/repos/bob/alpha/tarball/main",
+            [Reply::bytes(body.clone())],
+        ));
+        let scraper = scraper_with(
+            &transport,
+            Config {
+                stream_tarball: true,
+                extensions: vec!["rs".to_string()],
+                strip_components: 1,
+                ..Config::default()
+            },
+        );
+        let dir = temp_dir("tarball");
+        let repo_path = dir.join("alpha");
+        let log = RepoLog::new("bob/alpha", MAX_RETRIES);
+
+        let url = format!("https://github.com{}", ARCHIVE_PATH);
+        scraper
+            .download_and_extract(&url, &repo_path, ArchiveTarget::Directory, &log)
+            .await
+            .unwrap();
 
-        if !response.status().is_success() {
-            return Err(format!("HTTP {}", response.status()));
-        }
+        assert_eq!(transport.requests(), ["/repos/bob/alpha/tarball/main"]);
+        // The escaping entry and the filtered README never reach the disk,
+        // and neither does the archive itself.
+        assert_eq!(files_under(&dir), ["alpha/src/lib.rs"]);
+        assert_eq!(
+            fs::read_to_string(repo_path.join("src/lib.rs")).unwrap(),
+            "pub fn f() {}"
+        );
+        assert_eq!(scraper.files_skipped.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            scraper.archive_bytes_streamed.load(Ordering::Relaxed),
+            body.len() as u64
+        );
+        assert_eq!(
+            scraper.archive_commits.lock().unwrap().get("bob/alpha"),
+            Some(&commit.to_string())
+        );
+    }
 
-        let bytes = response.bytes().await.map_err(|e| e.to_string())?;
-        let zip_file = format!("{}.zip", repo_path.to_string_lossy());
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_tarballs_fail_without_leaving_a_directory() {
+        let transport = Arc::new(ScriptedTransport::default());
+        let scraper = scraper_with(
+            &transport,
+            Config {
+                stream_tarball: true,
+                ..Config::default()
+            },
+        );
+        let dir = temp_dir("tarball-missing");
+        let repo_path = dir.join("alpha");
+        let log = RepoLog::new("bob/alpha", MAX_RETRIES);
 
-        fs::write(&zip_file, &bytes).map_err(|e| e.to_string())?;
+        let url = format!("https://github.com{}", ARCHIVE_PATH);
+        let error = scraper
+            .download_and_extract(&url, &repo_path, ArchiveTarget::Directory, &log)
+            .await
+            .unwrap_err();
 
-        let result = extract_zip(&zip_file, repo_path);
-        fs::remove_file(&zip_file).ok();
+        assert!(error.is_missing_archive());
+        assert!(!repo_path.exists());
+    }
 
-        match result {
-            Ok(_) => Ok(get_dir_size(repo_path).unwrap_or(0)),
-            Err(e) => Err(e.to_string()),
+    #[test]
+    fn owner_case_collisions_follow_rename_policy() {
+        let root = Path::new("/backup/foo");
+        let first = repo_info("Foo/utils", false);
+        let second = repo_info("foo/utils", false);
+        for (policy, expected) in [
+            (
+                ConflictPolicy::Suffix,
+                Claim::Renamed {
+                    dir: "utils-2".to_string(),
+                    conflicts_with: "Foo/utils".to_string(),
+                },
+            ),
+            (
+                ConflictPolicy::UserPrefix,
+                Claim::Renamed {
+                    dir: "foo-utils".to_string(),
+                    conflicts_with: "Foo/utils".to_string(),
+                },
+            ),
+            (
+                ConflictPolicy::Skip,
+                Claim::Skipped {
+                    conflicts_with: "Foo/utils".to_string(),
+                },
+            ),
+        ] {
+            let mut claims = DirClaims::default();
+            assert_eq!(claims.claim(root, &first, policy), Claim::Own);
+            assert_eq!(claims.claim(root, &second, policy), expected);
         }
-    }
-}
 
-fn filter_repos(
-    repos: Vec<RepoInfo>,
-    language_filter: Option<&String>,
-    min_stars: u32,
-    max_size: Option<&u32>,
-    only_original: bool,
-    regex_filter: &Option<Regex>,
-) -> Vec<RepoInfo> {
-    repos
-        .into_iter()
-        .filter(|repo| {
-            if only_original && repo.is_fork {
-                return false;
+        // A third spelling steps past every name already handed out.
+        let mut claims = DirClaims::default();
+        let third = repo_info("FOO/Utils", false);
+        for repo in [&first, &second] {
+            claims.claim(root, repo, ConflictPolicy::Suffix);
+        }
+        assert_eq!(
+            claims.claim(root, &third, ConflictPolicy::Suffix),
+            Claim::Renamed {
+                dir: "Utils-3".to_string(),
+                conflicts_with: "Foo/utils".to_string(),
             }
+        );
+        // Other roots and names are unaffected.
+        assert_eq!(
+            claims.claim(Path::new("/backup/bar"), &first, ConflictPolicy::Suffix),
+            Claim::Own
+        );
+    }
 
-            if repo.stars < min_stars {
-                return false;
-            }
+    #[test]
+    fn parse_byte_size_accepts_common_spellings() {
+        const MIB: u64 = 1024 * 1024;
+        for (value, expected) in [
+            ("4096", 4096),
+            ("512M", 512 * MIB),
+            ("512m", 512 * MIB),
+            ("512MB", 512 * MIB),
+            ("512MiB", 512 * MIB),
+            ("2 G", 2 << 30),
+            (" 2 gib ", 2 << 30),
+            ("1.5G", 3 << 29),
+            ("0.5K", 512),
+            ("1T", 1 << 40),
+            ("100B", 100),
+        ] {
+            assert_eq!(parse_byte_size(value), Ok(expected), "{}", value);
+        }
 
-            if let Some(max_size_mb) = max_size {
-                if repo.size > *max_size_mb * 1024 {
-                    return false;
-                }
-            }
+        for value in [
+            "", "M", "12X", "1.2.3G", "-1G", "1e3", "512 M B", "0", "0.0001B",
+        ] {
+            assert!(parse_byte_size(value).is_err(), "{}", value);
+        }
+        assert_eq!(
+            parse_byte_size("20000000T"),
+            Err("size '20000000T' is too large".to_string())
+        );
+        assert!(parse_byte_size("12X").unwrap_err().contains("512MiB"));
+    }
 
-            if let Some(lang_filter) = language_filter {
-                match &repo.language {
-                    Some(lang) => {
-                        if lang.to_lowercase() != lang_filter.to_lowercase() {
-                            return false;
-                        }
-                    }
-                    None => return false,
-                }
-            }
+    #[test]
+    fn format_size_picks_the_unit_after_rounding() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        // 1023.949 KiB still prints as KiB; 1023.95 KiB would round to
+        // 1024.0 KiB and is promoted instead.
+        assert_eq!(format_size(1_048_524), "1023.9 KiB");
+        assert_eq!(format_size(1_048_525), "1.0 MiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+        assert_eq!(format_size((1 << 40) - 1), "1.0 TiB");
+        assert_eq!(format_size(5000 << 40), "5000.0 TiB");
+    }
 
-            if let Some(regex) = regex_filter {
-                if !regex.is_match(&repo.name) {
-                    return false;
-                }
-            }
+    #[test]
+    fn format_rate_divides_by_fractional_seconds() {
+        assert_eq!(format_rate(3 << 20, 2.0), "1.5 MiB/s");
+        assert_eq!(format_rate(1536, 0.5), "3.0 KiB/s");
+        assert_eq!(format_rate(1, 0.25), "4 B/s");
+        assert_eq!(format_rate(1024, 0.0), "0 B/s");
+    }
 
-            true
+    /// A repository as the API describes it.
+    fn repo_json(full_name: &str, fork: bool) -> serde_json::Value {
+        let (owner, name) = full_name.split_once('/').unwrap();
+        serde_json::json!({
+            "name": name,
+            "full_name": full_name,
+            "html_url": format!("https://github.com/{}", full_name),
+            "clone_url": format!("https://github.com/{}.git", full_name),
+            "language": "Rust",
+            "stargazers_count": 1,
+            "size": 10,
+            "fork": fork,
+            "default_branch": "main",
+            "pushed_at": "2024-01-01T00:00:00Z",
+            "owner": { "login": owner },
         })
-        .collect()
-}
+    }
 
-fn extract_zip(zip_path: &str, repo_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let file = fs::File::open(zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
+    fn repo_info(full_name: &str, fork: bool) -> RepoInfo {
+        serde_json::from_value(repo_json(full_name, fork)).unwrap()
+    }
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let outpath = match file.enclosed_name() {
-            Some(path) => path,
-            None => continue,
+    /// A fork's single-repository response, naming its parent and the root
+    /// of its network.
+    fn fork_details(full_name: &str, parent: &str, source: &str) -> Reply {
+        let mut details = repo_json(full_name, true);
+        details["parent"] = repo_json(parent, parent != source);
+        details["source"] = repo_json(source, false);
+        Reply::json(details)
+    }
+
+    fn names(repos: &[RepoInfo]) -> Vec<&str> {
+        repos.iter().map(|repo| repo.full_name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn follow_forks_upstream_walks_fork_chains() {
+        // bob/gamma forks dave/mid, itself a fork of dave/root; erin/other
+        // forks dave/root directly.
+        let transport = Arc::new(
+            ScriptedTransport::default()
+                .on(
+                    "/repos/bob/gamma",
+                    [fork_details("bob/gamma", "dave/mid", "dave/root")],
+                )
+                .on(
+                    "/repos/erin/other",
+                    [fork_details("erin/other", "dave/root", "dave/root")],
+                ),
+        );
+        let scraper = scraper(&transport);
+        let listed = || {
+            vec![
+                repo_info("bob/gamma", true),
+                repo_info("erin/other", true),
+                repo_info("carol/own", false),
+            ]
         };
 
-        let components: Vec<_> = outpath.components().collect();
-        let outpath = if components.len() > 1 {
-            repo_path.join(components[1..].iter().collect::<PathBuf>())
-        } else {
-            continue;
+        // The network root, not the fork's parent, and only once for both
+        // forks in its network.
+        let roots = scraper
+            .follow_forks_upstream(listed(), Upstream::Root)
+            .await;
+        assert_eq!(names(&roots), ["dave/root", "carol/own"]);
+
+        let parents = scraper
+            .follow_forks_upstream(listed(), Upstream::Parent)
+            .await;
+        assert_eq!(names(&parents), ["dave/mid", "dave/root", "carol/own"]);
+
+        // Only forks are looked up.
+        assert_eq!(transport.count("/repos/carol/own"), 0);
+    }
+
+    fn commit_at(date: &str) -> Reply {
+        Reply::json(serde_json::json!({
+            "sha": "0123456789abcdef0123456789abcdef01234567",
+            "commit": {
+                "committer": { "date": date },
+                "verification": { "verified": false, "reason": "unsigned" },
+            },
+        }))
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_most_recently_updated_branches() {
+        // The default branch is release/v2, its archive is gone, and there
+        // is no main or master to guess at.
+        let transport = Arc::new(
+            ScriptedTransport::default()
+                .on(
+                    "/repos/bob/alpha/branches",
+                    [
+                        Reply::json(serde_json::json!([
+                            { "name": "release/v2" },
+                            { "name": "old" },
+                            { "name": "newest" },
+                            { "name": "undated" },
+                            { "name": "middle" },
+                        ])),
+                        Reply::json(serde_json::json!([])),
+                    ],
+                )
+                .on(
+                    "/repos/bob/alpha/commits/old",
+                    [commit_at("2021-06-01T00:00:00Z")],
+                )
+                .on(
+                    "/repos/bob/alpha/commits/newest",
+                    [commit_at("2024-03-01T00:00:00Z")],
+                )
+                .on(
+                    "/repos/bob/alpha/commits/middle",
+                    [commit_at("2023-01-15T00:00:00Z")],
+                ),
+        );
+        let scraper = scraper(&transport);
+        let mut repo = repo_info("bob/alpha", false);
+        repo.default_branch = "release/v2".to_string();
+        let repo_path = temp_dir("fallback").join("alpha");
+
+        let result = scraper
+            .download_default_branch(
+                &repo,
+                &repo_path,
+                ArchiveTarget::Directory,
+                &RepoLog::new("bob/alpha", MAX_RETRIES),
+            )
+            .await;
+        assert!(result.unwrap_err().is_missing_archive());
+
+        let archives: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .filter_map(|path| {
+                path.strip_prefix("/bob/alpha/archive/refs/heads/")
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            archives,
+            [
+                "release/v2.zip",
+                "newest.zip",
+                "middle.zip",
+                "old.zip",
+                "undated.zip"
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_schema_covers_every_emitted_field() {
+        let downloaded = Ok(Download {
+            bytes: 29,
+            detected_language: Some("Rust".to_string()),
+            language_bytes: BTreeMap::from([("Rust".to_string(), 1000)]),
+            tag: Some("v1.0.0".to_string()),
+            branches: vec!["main".to_string()],
+            bundle: Some("small-001.zip".to_string()),
+            signature: Some(CommitSignature {
+                git_ref: "main".to_string(),
+                sha: "0123456789abcdef0123456789abcdef01234567".to_string(),
+                verified: true,
+                reason: "valid".to_string(),
+            }),
+            mirror: Some(MirrorPush {
+                remote: "git@example.com:bob/alpha.git".to_string(),
+                error: Some("rejected".to_string()),
+            }),
+            cloned: true,
+            duplicate_of: Some("bob/beta".to_string()),
+            dir: Some("alpha@main-0123456".to_string()),
+            tree_depth: Some(5),
+            commit: Some("0123456789abcdef0123456789abcdef01234567".to_string()),
+            conflicts_with: Some("Bob/alpha".to_string()),
+        });
+        let mut repo = repo_info("bob/alpha", false);
+        repo.id = 7;
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            owner: "bob".to_string(),
+            elapsed_secs: 1.5,
+            files_skipped: 1,
+            dedupe_bytes_saved: 2,
+            dedupe_files_linked: 3,
+            archive_bytes_streamed: 4,
+            repos: vec![RepoRecord::new(&repo, downloaded)],
         };
+        let summary =
+            serde_json::to_value(Summary::new(&manifest).with_flag_depth(Some(1))).unwrap();
+        let schema = summary_schema();
 
-        if file.name().ends_with('/') {
-            fs::create_dir_all(&outpath)?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                fs::create_dir_all(p)?;
+        // Every key an object emits is described, and every required key
+        // is emitted.
+        let check = |value: &serde_json::Value, schema: &serde_json::Value| {
+            let keys: Vec<&String> = value.as_object().unwrap().keys().collect();
+            let properties = schema["properties"].as_object().unwrap();
+            for key in &keys {
+                assert!(
+                    properties.contains_key(*key),
+                    "{} is not in the schema",
+                    key
+                );
             }
-            let mut outfile = fs::File::create(&outpath)?;
-            copy(&mut file, &mut outfile)?;
+            for required in schema["required"].as_array().unwrap() {
+                assert!(keys.contains(&&required.as_str().unwrap().to_string()));
+            }
+            assert_eq!(
+                keys.len(),
+                properties.len(),
+                "a schema property is never emitted"
+            );
+        };
+        let defs = &schema["$defs"];
+        check(&summary, &schema);
+        for (key, def) in [
+            ("languages", "LanguageTotal"),
+            ("language_bytes", "LanguageTotal"),
+            ("largest", "RepoSize"),
+            ("deep", "RepoDepth"),
+            ("repos", "RepoRecord"),
+        ] {
+            check(&summary[key][0], &defs[def]);
         }
+        let record = &summary["repos"][0];
+        check(&record["signature"], &defs["CommitSignature"]);
+        check(&record["mirror"], &defs["MirrorPush"]);
     }
 
-    Ok(())
-}
+    #[test]
+    fn manifests_round_trip_in_every_format() {
+        let downloaded = Ok(Download {
+            bytes: 29,
+            detected_language: None,
+            language_bytes: BTreeMap::from([("Rust".to_string(), 1000)]),
+            tag: Some("v1.0.0".to_string()),
+            branches: Vec::new(),
+            bundle: None,
+            signature: None,
+            mirror: None,
+            cloned: false,
+            duplicate_of: None,
+            dir: None,
+            tree_depth: Some(2),
+            commit: Some("0123456789abcdef0123456789abcdef01234567".to_string()),
+            conflicts_with: None,
+        });
+        let mut untyped = repo_info("bob/beta", false);
+        untyped.language = None;
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            owner: "bob".to_string(),
+            elapsed_secs: 1.5,
+            files_skipped: 0,
+            dedupe_bytes_saved: 0,
+            dedupe_files_linked: 0,
+            archive_bytes_streamed: 0,
+            repos: vec![
+                RepoRecord::new(&repo_info("bob/alpha", false), downloaded),
+                RepoRecord::new(
+                    &untyped,
+                    Err("HTTP 500: \"quoted\"\nline".to_string().into()),
+                ),
+            ],
+        };
 
-fn get_dir_size(dir: &Path) -> Result<u64, std::io::Error> {
-    let mut size = 0;
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let metadata = entry.metadata()?;
+        for format in MANIFEST_FORMATS {
+            let text = format.encode(&manifest).unwrap();
+            let decoded: Manifest = format.decode(&text).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&manifest).unwrap(),
+                "{}",
+                format.extension()
+            );
+        }
+    }
 
-        if metadata.is_file() {
-            size += metadata.len();
-        } else if metadata.is_dir() {
-            size += get_dir_size(&entry.path()).unwrap_or(0);
+    #[test]
+    fn account_metadata_round_trips_protection_with_nulls() {
+        let protection = serde_json::json!({
+            "required_status_checks": {
+                "strict": true,
+                "contexts": ["ci", null],
+                "checks": [{ "context": "ci", "app_id": null }],
+            },
+            "required_pull_request_reviews": {
+                "dismissal_restrictions": {},
+                "required_approving_review_count": 1,
+            },
+            "restrictions": null,
+            "enforce_admins": { "enabled": false },
+        });
+        let saved = serde_json::json!({
+            "required_status_checks": {
+                "strict": true,
+                "contexts": ["ci"],
+                "checks": [{ "context": "ci" }],
+            },
+            "required_pull_request_reviews": {
+                "dismissal_restrictions": {},
+                "required_approving_review_count": 1,
+            },
+            "enforce_admins": { "enabled": false },
+        });
+        let repo = AccountRepo {
+            full_name: "bob/alpha".to_string(),
+            html_url: "https://github.com/bob/alpha".to_string(),
+            default_branch: "main".to_string(),
+            description: None,
+            homepage: None,
+            topics: Vec::new(),
+            visibility: Some("public".to_string()),
+            fork: false,
+            branch_protection: Some(protection),
+        };
+
+        for format in MANIFEST_FORMATS {
+            let dir = temp_dir(&format!("account-{}", format.extension()));
+            AccountMetadata::update(&dir, "bob", vec![&repo], format).unwrap();
+
+            let text = fs::read_to_string(dir.join(AccountMetadata::file_name(format))).unwrap();
+            let decoded: AccountMetadata = format.decode(&text).unwrap();
+            assert_eq!(decoded.repos.len(), 1);
+            assert_eq!(
+                decoded.repos[0].branch_protection.as_ref(),
+                Some(&saved),
+                "{}",
+                format.extension()
+            );
         }
     }
-    Ok(size)
+
+    #[test]
+    fn manifests_accept_hand_written_syntax() {
+        let toml: Manifest = ManifestFormat::Toml
+            .decode(
+                "version = 1\nowner = \"\"\"\nbob\"\"\"\nelapsed_secs = 0.0\nfiles_skipped = 0\n\
+                 dedupe_bytes_saved = 0\ndedupe_files_linked = 0\nrepos = []\n",
+            )
+            .unwrap();
+        assert_eq!(toml.owner, "bob");
+
+        let yaml: Manifest = ManifestFormat::Yaml
+            .decode(
+                "version: 1\nowner: bob\nelapsed_secs: 0.0\nfiles_skipped: &zero 0\n\
+                 dedupe_bytes_saved: *zero\ndedupe_files_linked: *zero\nrepos:\n\
+                 - {name: alpha, full_name: bob/alpha, language: null, stars: 1, size_kb: 10, \
+                 status: downloaded, bytes: 29, error: null}\n",
+            )
+            .unwrap();
+        assert_eq!(yaml.repos[0].full_name, "bob/alpha");
+    }
 }